@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::{Context, Result};
+use rustix::fs::FileType;
+use std::{
+    env,
+    os::unix::io::{BorrowedFd, RawFd},
+};
+
+use crate::{cli::Args, config::Config, environment, session};
+
+/// Validates the startup environment without entering the event loop.
+///
+/// Prints one line per check and fails, if any of them did.
+pub fn run(args: &Args, config: &Config) -> Result<()> {
+    let mut failed = 0;
+    let mut report = |name: &str, result: Result<String>| match result {
+        Ok(details) => println!("[ OK ] {name}: {details}"),
+        Err(err) => {
+            println!("[FAIL] {name}: {err:#}");
+            failed += 1;
+        }
+    };
+
+    report("session socket", check_session_fd(args.session_fd));
+    report(
+        "environment",
+        environment::get_env(
+            args.wayland_display.as_deref(),
+            args.compositor,
+            args.no_x11,
+            config,
+        )
+        .map(|env| {
+            let mut names = env.keys().map(String::as_str).collect::<Vec<_>>();
+            names.sort_unstable();
+            names.join(", ")
+        }),
+    );
+    report("upstream", check_upstream(args));
+
+    anyhow::ensure!(failed == 0, "{failed} check(s) failed");
+    Ok(())
+}
+
+fn check_session_fd(fd: Option<RawFd>) -> Result<String> {
+    let fd = fd.context("COSMIC_SESSION_SOCK is not set")?;
+    anyhow::ensure!(fd >= 0, "fd {fd} is invalid");
+    // SAFETY: only borrowed for the duration of the checks, we never close it
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    let stat = rustix::fs::fstat(borrowed).with_context(|| format!("fd {fd} is not open"))?;
+    anyhow::ensure!(
+        FileType::from_raw_mode(stat.st_mode) == FileType::Socket,
+        "fd {fd} is not a socket"
+    );
+    let creds = rustix::net::sockopt::get_socket_peercred(borrowed)
+        .context("Failed to read peer credentials")?;
+    let uid = rustix::process::getuid();
+    anyhow::ensure!(
+        creds.uid == uid,
+        "peer runs as uid {}, expected {}",
+        creds.uid.as_raw(),
+        uid.as_raw()
+    );
+    Ok(format!(
+        "fd {fd}, peer pid {}",
+        creds.pid.as_raw_nonzero().get()
+    ))
+}
+
+fn check_upstream(args: &Args) -> Result<String> {
+    let (address, _) = session::connect_first(
+        &args.upstream(),
+        args.wayland_display.as_deref(),
+        environment::upstream_runtime_dir().as_deref(),
+    )
+    .context("Failed to connect to upstream")?;
+    if env::var_os("WAYLAND_SOCKET").is_some() {
+        return Ok(format!(
+            "{address} (WAYLAND_SOCKET is used for the first client)"
+        ));
+    }
+    Ok(address.to_string())
+}
@@ -0,0 +1,227 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use clap::{builder::BoolishValueParser, Parser, ValueEnum};
+use std::{os::unix::io::RawFd, path::PathBuf, str::FromStr, sync::LazyLock};
+
+use crate::session::PROTOCOL_VERSION;
+
+/// Crate version along with the session protocol version it speaks, as shown by `--version`.
+pub static VERSION: LazyLock<String> = LazyLock::new(|| {
+    format!(
+        "{} (session protocol {PROTOCOL_VERSION})",
+        env!("CARGO_PKG_VERSION")
+    )
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Where log output goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogTarget {
+    Stderr,
+    /// Native journald entries with priorities and fields, falls back to stderr without journald
+    Journald,
+}
+
+/// Compositor whose ipc variables are forwarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compositor {
+    /// Forward the variables of every compositor that are set
+    Auto,
+    Sway,
+    Niri,
+    Hyprland,
+}
+
+/// Compositor to forward privileged clients to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upstream {
+    /// Wayland socket name, path or `@`-prefixed abstract name
+    Unix(String),
+    /// `host:port` of a remote compositor
+    Tcp(String),
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(address) = s.strip_prefix("tcp://") {
+            Ok(Upstream::Tcp(address.to_string()))
+        } else if let Some(socket) = s.strip_prefix("unix://") {
+            Ok(Upstream::Unix(socket.to_string()))
+        } else {
+            Err(format!(
+                "expected tcp://<host>:<port> or unix://<socket>, got {s:?}"
+            ))
+        }
+    }
+}
+
+/// Alternative compositor ipc entry point for cosmic-session.
+///
+/// Every option can also be provided through the environment variable noted next to it.
+#[derive(Debug, Parser)]
+#[command(version = VERSION.as_str(), about)]
+pub struct Args {
+    /// File descriptor of the socket connected to cosmic-session
+    #[arg(long, env = "COSMIC_SESSION_SOCK", required_unless_present_any = ["check", "health", "stdin_control"])]
+    pub session_fd: Option<RawFd>,
+    /// Wayland display to advertise and forward privileged clients to
+    #[arg(long, env = "WAYLAND_DISPLAY")]
+    pub wayland_display: Option<String>,
+    /// Colon-separated wayland displays to forward privileged clients to instead of the advertised one,
+    /// the first one accepting a connection is used
+    #[arg(long, env = "COSMIC_ALT_UPSTREAM_WAYLAND")]
+    pub upstream_wayland: Option<String>,
+    /// Compositor to forward privileged clients to, as `unix://<socket>` or `tcp://<host>:<port>`
+    #[arg(long, env = "COSMIC_ALT_UPSTREAM", conflicts_with = "upstream_wayland")]
+    pub upstream: Option<Upstream>,
+    /// Log filter in `RUST_LOG` syntax
+    #[arg(long, env = "RUST_LOG", default_value = "info")]
+    pub log_level: String,
+    /// Format of the log output
+    #[arg(long, env = "COSMIC_ALT_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    /// Destination of the log output
+    #[arg(long, env = "COSMIC_ALT_LOG_TARGET", value_enum, default_value_t = LogTarget::Stderr)]
+    pub log_target: LogTarget,
+    /// Compositor whose ipc variables are forwarded to cosmic-session
+    #[arg(long, env = "COSMIC_ALT_COMPOSITOR", value_enum, default_value_t = Compositor::Auto)]
+    pub compositor: Compositor,
+    /// Don't forward DISPLAY and XAUTHORITY, so privileged clients can't pick X11
+    #[arg(long, env = "COSMIC_ALT_NO_X11", value_parser = BoolishValueParser::new())]
+    pub no_x11: bool,
+    /// Comma-separated list of additional variables to forward to cosmic-session
+    #[arg(long, env = "COSMIC_ALT_FORWARD_ENV")]
+    pub forward_env: Option<String>,
+    /// Forward our whole environment to cosmic-session instead of a curated set of variables
+    #[arg(long, env = "COSMIC_ALT_FORWARD_ALL_ENV", value_parser = BoolishValueParser::new(), num_args = 0..=1, default_missing_value = "true")]
+    pub forward_all_env: Option<bool>,
+    /// Comma-separated list of additional variables to never forward, `PREFIX_*` matches a prefix
+    #[arg(long, env = "COSMIC_ALT_ENV_DENY")]
+    pub env_deny: Option<String>,
+    /// File of `KEY=VALUE` lines to forward to cosmic-session in addition to the environment
+    #[arg(long, env = "COSMIC_ALT_ENV_FILE")]
+    pub env_file: Option<PathBuf>,
+    /// Let variables from the env file override the ones forwarded from our environment
+    #[arg(long, env = "COSMIC_ALT_ENV_FILE_OVERRIDE", value_parser = BoolishValueParser::new(), num_args = 0..=1, default_missing_value = "true")]
+    pub env_file_override: Option<bool>,
+    /// Maximum amount of concurrently proxied privileged clients [default: 256]
+    #[arg(long, env = "COSMIC_ALT_MAX_CLIENTS")]
+    pub max_clients: Option<usize>,
+    /// Size in bytes of the buffer used for a single proxied read [default: 65536]
+    #[arg(long, env = "COSMIC_ALT_PROXY_BUFSIZE")]
+    pub proxy_buffer_size: Option<usize>,
+    /// Requested size in bytes of the socket buffers of proxied connections [default: kernel default]
+    #[arg(long, env = "COSMIC_ALT_SOCKET_BUFSIZE")]
+    pub socket_buffer_size: Option<usize>,
+    /// Splice proxied data not carrying any fds, instead of copying it through userspace
+    #[arg(long, env = "COSMIC_ALT_SPLICE", value_parser = BoolishValueParser::new(), num_args = 0..=1, default_missing_value = "true")]
+    pub splice: Option<bool>,
+    /// Re-arm the session socket only after handling a whole message, instead of level-triggering it
+    #[arg(long, env = "COSMIC_ALT_SESSION_ONESHOT", value_parser = BoolishValueParser::new(), num_args = 0..=1, default_missing_value = "true")]
+    pub session_oneshot: Option<bool>,
+    /// Seconds cosmic-session has to read our startup messages, 0 waits forever [default: 5]
+    #[arg(long, env = "COSMIC_ALT_HANDSHAKE_TIMEOUT")]
+    pub handshake_timeout: Option<u64>,
+    /// Interval in seconds to ping cosmic-session in
+    #[arg(long, env = "COSMIC_ALT_PING_INTERVAL")]
+    pub ping_interval: Option<u64>,
+    /// Seconds without traffic after which a proxied privileged client is closed [default: disabled]
+    #[arg(long, env = "COSMIC_ALT_IDLE_TIMEOUT")]
+    pub idle_timeout: Option<u64>,
+    /// Bytes per second forwarded in each direction of a privileged client [default: unlimited]
+    #[arg(long, env = "COSMIC_ALT_RATE_LIMIT", value_parser = clap::value_parser!(u64).range(1..))]
+    pub rate_limit: Option<u64>,
+    /// Log the header of every proxied wayland message at trace level
+    #[arg(long, env = "COSMIC_ALT_TRACE_PROTOCOL", value_parser = BoolishValueParser::new(), num_args = 0..=1, default_missing_value = "true")]
+    pub trace_protocol: Option<bool>,
+    /// Receive the credentials privileged clients send along with their data, and log them
+    #[arg(long, env = "COSMIC_ALT_CLIENT_CREDENTIALS", value_parser = BoolishValueParser::new(), num_args = 0..=1, default_missing_value = "true")]
+    pub client_credentials: Option<bool>,
+    /// Path of a unix socket to serve metrics on
+    #[arg(long, env = "COSMIC_ALT_METRICS_SOCK")]
+    pub metrics_sock: Option<PathBuf>,
+    /// Path or `@`-prefixed abstract name of a unix socket answering pings and stats requests of local tools
+    #[arg(long, env = "COSMIC_ALT_CONTROL_SOCK")]
+    pub control_sock: Option<PathBuf>,
+    /// Handle framed session messages from stdin as well, for testing without cosmic-session
+    #[arg(long, env = "COSMIC_ALT_STDIN_CONTROL", value_parser = BoolishValueParser::new())]
+    pub stdin_control: bool,
+    /// Validate the environment and the upstream compositor, then exit
+    #[arg(long, env = "COSMIC_ALT_CHECK", value_parser = BoolishValueParser::new())]
+    pub check: bool,
+    /// Ping the instance listening on the control socket and exit 0, if it answers in time
+    #[arg(long, env = "COSMIC_ALT_HEALTH", value_parser = BoolishValueParser::new(), requires = "control_sock")]
+    pub health: bool,
+}
+
+impl Args {
+    /// Compositors to try forwarding privileged clients to in order, if they differ from `wayland_display`.
+    pub fn upstream(&self) -> Vec<Upstream> {
+        if let Some(upstream) = self.upstream.clone() {
+            return vec![upstream];
+        }
+        self.upstream_wayland
+            .iter()
+            .flat_map(|list| list.split(':'))
+            .filter(|socket| !socket.is_empty())
+            .map(|socket| Upstream::Unix(socket.to_string()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Args {
+        Args::try_parse_from(
+            ["cosmic-ext-alternative-startup", "--session-fd", "3"]
+                .iter()
+                .chain(args),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn upstream_from_str() {
+        assert_eq!(
+            "tcp://localhost:4000".parse(),
+            Ok(Upstream::Tcp(String::from("localhost:4000")))
+        );
+        assert_eq!(
+            "unix://wayland-1".parse(),
+            Ok(Upstream::Unix(String::from("wayland-1")))
+        );
+        assert!("wayland-1".parse::<Upstream>().is_err());
+    }
+
+    #[test]
+    fn upstream_from_args() {
+        assert_eq!(parse(&[]).upstream(), []);
+        assert_eq!(
+            parse(&["--upstream", "tcp://host:1"]).upstream(),
+            [Upstream::Tcp(String::from("host:1"))]
+        );
+        assert_eq!(
+            parse(&["--upstream-wayland", "wayland-1::/run/wayland-2:"]).upstream(),
+            [
+                Upstream::Unix(String::from("wayland-1")),
+                Upstream::Unix(String::from("/run/wayland-2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_reports_missing_session_socket() {
+        let args = Args::try_parse_from(["cosmic-ext-alternative-startup", "--check"]).unwrap();
+        assert!(args.check);
+        assert_eq!(args.session_fd, None);
+    }
+}
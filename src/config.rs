@@ -0,0 +1,294 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::warn;
+
+use crate::cli::Args;
+
+/// Upper bound for `proxy_buffer_size`, larger reads don't pay off and waste memory.
+pub const MAX_PROXY_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// Settings read from `$XDG_CONFIG_HOME/cosmic-ext-alternative-startup/config.toml`.
+///
+/// Missing keys fall back to the compiled defaults, command line flags and
+/// environment variables take precedence over the file.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Additional environment variables to forward to cosmic-session
+    pub forward_env: Vec<String>,
+    /// Whether to forward all inherited variables, not just the curated ones
+    pub forward_all_env: bool,
+    /// Additional variables to never forward, on top of the built-in denylist
+    pub env_deny: Vec<String>,
+    /// File of `KEY=VALUE` lines to forward to cosmic-session
+    pub env_file: Option<PathBuf>,
+    /// Whether the env file takes precedence over our own environment
+    pub env_file_override: bool,
+    /// Maximum amount of concurrently proxied privileged clients
+    pub max_clients: usize,
+    /// Size of the buffer used for a single proxied read
+    pub proxy_buffer_size: usize,
+    /// Requested SO_RCVBUF and SO_SNDBUF of proxied sockets, the kernel default if unset
+    pub socket_buffer_size: Option<usize>,
+    /// Whether fd-less proxied data is spliced between the sockets
+    pub splice: bool,
+    /// Whether the session socket source is re-armed after every message instead of level-triggered
+    pub session_oneshot: bool,
+    /// Seconds cosmic-session has to read our startup handshake, 0 waits forever
+    pub handshake_timeout_secs: u64,
+    /// Delays in milliseconds between attempts to connect a privileged client
+    pub connect_retry_delays_ms: Vec<u64>,
+    /// Seconds without traffic after which a proxied privileged client is closed
+    pub idle_timeout_secs: Option<u64>,
+    /// Bytes per second forwarded in each direction of a proxied privileged client
+    pub rate_limit_bytes_per_sec: Option<u64>,
+    /// Log the header of every proxied wayland message at trace level
+    pub trace_protocol: bool,
+    /// Whether privileged clients' credentials are received with SO_PASSCRED and logged
+    pub client_credentials: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            forward_env: Vec::new(),
+            forward_all_env: false,
+            env_deny: Vec::new(),
+            env_file: None,
+            env_file_override: false,
+            max_clients: 256,
+            proxy_buffer_size: 64 * 1024,
+            socket_buffer_size: None,
+            splice: false,
+            session_oneshot: false,
+            handshake_timeout_secs: 5,
+            connect_retry_delays_ms: vec![50, 100, 200],
+            idle_timeout_secs: None,
+            rate_limit_bytes_per_sec: None,
+            trace_protocol: false,
+            client_credentials: false,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file, returning the defaults if it doesn't exist.
+    pub fn load() -> Result<Config> {
+        match config_path() {
+            Some(path) => Config::load_from(&path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Loads the config file at `path`, returning the defaults if it doesn't exist.
+    pub fn load_from(path: &Path) -> Result<Config> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+        Config::parse(&content).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Parses the contents of a config file.
+    pub fn parse(content: &str) -> Result<Config> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Rejects invalid values after [`Config::merge_args`], capping the ones that are merely too large.
+    pub fn validate(&mut self) -> Result<()> {
+        anyhow::ensure!(
+            self.proxy_buffer_size > 0,
+            "proxy_buffer_size must not be 0"
+        );
+        if self.proxy_buffer_size > MAX_PROXY_BUFFER_SIZE {
+            warn!(
+                proxy_buffer_size = self.proxy_buffer_size,
+                max = MAX_PROXY_BUFFER_SIZE,
+                "proxy_buffer_size is too large, capping it"
+            );
+            self.proxy_buffer_size = MAX_PROXY_BUFFER_SIZE;
+        }
+        anyhow::ensure!(
+            self.socket_buffer_size != Some(0),
+            "socket_buffer_size must not be 0"
+        );
+        anyhow::ensure!(
+            self.rate_limit_bytes_per_sec != Some(0),
+            "rate_limit_bytes_per_sec must not be 0"
+        );
+        Ok(())
+    }
+
+    /// Applies the command line flags and environment variables on top of the file.
+    pub fn merge_args(&mut self, args: &Args) {
+        if let Some(list) = args.forward_env.as_deref() {
+            self.forward_env = parse_env_list(list).map(String::from).collect();
+        }
+        if let Some(forward_all_env) = args.forward_all_env {
+            self.forward_all_env = forward_all_env;
+        }
+        if let Some(list) = args.env_deny.as_deref() {
+            self.env_deny = parse_env_list(list).map(String::from).collect();
+        }
+        if let Some(path) = args.env_file.clone() {
+            self.env_file = Some(path);
+        }
+        if let Some(env_file_override) = args.env_file_override {
+            self.env_file_override = env_file_override;
+        }
+        if let Some(size) = args.proxy_buffer_size {
+            self.proxy_buffer_size = size;
+        }
+        if let Some(size) = args.socket_buffer_size {
+            self.socket_buffer_size = Some(size);
+        }
+        if let Some(splice) = args.splice {
+            self.splice = splice;
+        }
+        if let Some(session_oneshot) = args.session_oneshot {
+            self.session_oneshot = session_oneshot;
+        }
+        if let Some(timeout) = args.handshake_timeout {
+            self.handshake_timeout_secs = timeout;
+        }
+        if let Some(max_clients) = args.max_clients {
+            self.max_clients = max_clients;
+        }
+        if let Some(idle_timeout) = args.idle_timeout {
+            self.idle_timeout_secs = Some(idle_timeout);
+        }
+        if let Some(rate_limit) = args.rate_limit {
+            self.rate_limit_bytes_per_sec = Some(rate_limit);
+        }
+        if let Some(trace_protocol) = args.trace_protocol {
+            self.trace_protocol = trace_protocol;
+        }
+        if let Some(client_credentials) = args.client_credentials {
+            self.client_credentials = client_credentials;
+        }
+    }
+
+    /// Delay before the given retry `attempt` to connect a privileged client, if any are left.
+    pub fn connect_retry_delay(&self, attempt: usize) -> Option<Duration> {
+        self.connect_retry_delays_ms
+            .get(attempt)
+            .copied()
+            .map(Duration::from_millis)
+    }
+
+    /// Time cosmic-session has to read our startup handshake, 0 disables it.
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        Some(self.handshake_timeout_secs)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Time without traffic after which a proxied privileged client is closed, 0 disables it.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+}
+
+/// Splits a comma-separated list of variable names, ignoring whitespace and empty entries.
+fn parse_env_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+}
+
+fn config_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(
+        config_dir
+            .join("cosmic-ext-alternative-startup")
+            .join("config.toml"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn args(args: &[&str]) -> Args {
+        Args::try_parse_from(
+            ["cosmic-ext-alternative-startup", "--session-fd", "3"]
+                .iter()
+                .chain(args),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_readme_sample() {
+        let readme = include_str!("../README.md");
+        let sample = readme
+            .split("```toml\n")
+            .nth(1)
+            .and_then(|rest| rest.split("```").next())
+            .unwrap();
+        let config = Config::parse(sample).unwrap();
+        assert_eq!(config.forward_env, ["XCURSOR_THEME"]);
+        assert_eq!(config.env_deny, ["GTK_MODULES", "QT_PLUGIN_*"]);
+        assert_eq!(config.socket_buffer_size, Some(262144));
+        assert_eq!(config.idle_timeout(), Some(Duration::from_secs(300)));
+        assert_eq!(config.rate_limit_bytes_per_sec, Some(1048576));
+    }
+
+    #[test]
+    fn parse_falls_back_to_defaults() {
+        let config = Config::parse("max_clients = 4\n").unwrap();
+        assert_eq!(config.max_clients, 4);
+        assert_eq!(
+            config.proxy_buffer_size,
+            Config::default().proxy_buffer_size
+        );
+        assert!(Config::parse("no_such_key = 1\n").is_err());
+        assert!(Config::parse("max_clients = \"many\"\n").is_err());
+    }
+
+    #[test]
+    fn load_from_missing_file_uses_defaults() {
+        let path = env::temp_dir().join(format!("cosmic-alt-test-{}-config", std::process::id()));
+        let config = Config::load_from(&path).unwrap();
+        assert_eq!(config.max_clients, Config::default().max_clients);
+
+        fs::write(&path, "splice = true\n").unwrap();
+        assert!(Config::load_from(&path).unwrap().splice);
+        fs::write(&path, "splice = \n").unwrap();
+        assert!(Config::load_from(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn merge_args_overrides_file() {
+        let mut config = Config::parse("splice = true\ntrace_protocol = true\n").unwrap();
+        config.merge_args(&args(&[
+            "--splice=false",
+            "--session-oneshot",
+            "--max-clients",
+            "2",
+        ]));
+        assert!(!config.splice);
+        assert!(config.session_oneshot);
+        // unset flags keep the value of the file
+        assert!(config.trace_protocol);
+        assert_eq!(config.max_clients, 2);
+    }
+}
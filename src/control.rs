@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::{Context, Result};
+use calloop::{generic::Generic, Interest, Mode, PostAction};
+use rustix::fs::Mode as FileMode;
+use std::{
+    io::{ErrorKind, Read, Write},
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixListener, UnixStream},
+        },
+    },
+    path::Path,
+    time::Duration,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    session::{self, Message, MAX_MESSAGE_LEN},
+    socket, State,
+};
+
+/// How long `--health` waits for the running instance to answer.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Listens on `path` for local tools talking the session protocol, e.g. `--health`.
+///
+/// A `@`-prefixed `path` is an abstract socket name, which works without a writable filesystem.
+/// Only `ping` and `stats_request` are answered, everything else is reserved to cosmic-session.
+pub fn setup_listener(state: &mut State, path: &Path) -> Result<()> {
+    let listener = match abstract_name(path) {
+        Some(name) => {
+            SocketAddr::from_abstract_name(name).and_then(|addr| UnixListener::bind_addr(&addr))
+        }
+        None => bind_path(path),
+    }
+    .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    listener
+        .set_nonblocking(true)
+        .context("Failed to make control socket non-blocking")?;
+
+    state
+        .loop_handle
+        .insert_source(
+            Generic::new(listener, Interest::READ, Mode::Level),
+            |_, listener, state| {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => accept_client(state, stream),
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                        Err(err) => {
+                            warn!(?err, "Failed to accept control client");
+                            break;
+                        }
+                    }
+                }
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|err| err.error)
+        .context("Failed to init the control socket source")?;
+    Ok(())
+}
+
+/// Binds a filesystem socket only accessible by our own user, replacing a stale one.
+fn bind_path(path: &Path) -> std::io::Result<UnixListener> {
+    socket::remove_stale_socket(path)?;
+    // the socket is created with 0600 right away, unlike a chmod after binding
+    let umask = rustix::process::umask(FileMode::from_raw_mode(0o177));
+    let listener = UnixListener::bind(path);
+    rustix::process::umask(umask);
+    listener
+}
+
+/// The abstract socket name of `path`, if it is `@`-prefixed.
+fn abstract_name(path: &Path) -> Option<&[u8]> {
+    path.as_os_str().as_bytes().strip_prefix(b"@")
+}
+
+fn accept_client(state: &mut State, stream: UnixStream) {
+    if let Err(err) = stream.set_nonblocking(true) {
+        debug!(?err, "Failed to make control client non-blocking");
+        return;
+    }
+    // frames of a client may arrive in pieces, so they are collected here
+    let mut buf = Vec::new();
+    let res = state.loop_handle.insert_source(
+        Generic::new(stream, Interest::READ, Mode::Level),
+        move |_, stream, state| {
+            // SAFETY: We don't drop the stream!
+            let stream = unsafe { stream.get_mut() };
+            let mut chunk = [0; 4096];
+            let mut closed = false;
+            loop {
+                match stream.read(&mut chunk) {
+                    Ok(0) => {
+                        closed = true;
+                        break;
+                    }
+                    Ok(read) => buf.extend_from_slice(&chunk[..read]),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                    Err(err) => {
+                        debug!(?err, "Error reading from control client");
+                        return Ok(PostAction::Remove);
+                    }
+                }
+            }
+            // a client might shut down its end right after sending its request
+            let action = handle_frames(state, stream, &mut buf);
+            Ok(if closed { PostAction::Remove } else { action })
+        },
+    );
+    if let Err(err) = res {
+        warn!(err = ?err.error, "Failed to init control client source");
+    }
+}
+
+/// Answers every complete frame in `buf`, leaving a partial one for the next read.
+fn handle_frames(state: &mut State, stream: &mut UnixStream, buf: &mut Vec<u8>) -> PostAction {
+    while buf.len() >= 4 {
+        let size = u32::from_ne_bytes(buf[..4].try_into().unwrap());
+        if size > MAX_MESSAGE_LEN {
+            warn!(size, "Control message exceeds maximum message length");
+            return PostAction::Remove;
+        }
+        let end = 4 + size as usize;
+        if buf.len() < end {
+            break;
+        }
+        let reply = match serde_json::from_slice::<Message>(&buf[4..end]) {
+            Ok(Message::Ping) => Message::Pong,
+            Ok(Message::StatsRequest) => Message::StatsResponse {
+                counts: state.metrics.session_message_counts(),
+            },
+            Ok(message) => {
+                warn!(tag = message.name(), "Unsupported control socket message");
+                return PostAction::Remove;
+            }
+            Err(err) => {
+                warn!(?err, "Invalid control socket message");
+                return PostAction::Remove;
+            }
+        };
+        buf.drain(..end);
+        // replies are far smaller than the socket buffer, so this doesn't block
+        let written = session::encode_message(&reply)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(stream.write_all(&bytes)?));
+        if let Err(err) = written {
+            debug!(?err, "Failed to answer control client");
+            return PostAction::Remove;
+        }
+    }
+    PostAction::Continue
+}
+
+/// Pings the instance listening on the control socket `path`, failing unless it answers in time.
+pub fn health(path: &Path) -> Result<()> {
+    let mut stream = match abstract_name(path) {
+        Some(name) => {
+            SocketAddr::from_abstract_name(name).and_then(|addr| UnixStream::connect_addr(&addr))
+        }
+        None => UnixStream::connect(path),
+    }
+    .with_context(|| format!("Failed to connect to control socket {}", path.display()))?;
+    stream.set_read_timeout(Some(HEALTH_TIMEOUT))?;
+    stream.set_write_timeout(Some(HEALTH_TIMEOUT))?;
+    stream
+        .write_all(&session::encode_message(&Message::Ping)?)
+        .context("Failed to send ping")?;
+
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).context("No answer to ping")?;
+    let size = u32::from_ne_bytes(len);
+    anyhow::ensure!(
+        size <= MAX_MESSAGE_LEN,
+        "Answer of {size} bytes is too long"
+    );
+    let mut body = vec![0; size as usize];
+    stream
+        .read_exact(&mut body)
+        .context("Incomplete answer to ping")?;
+    match serde_json::from_slice::<Message>(&body).context("Invalid answer to ping")? {
+        Message::Pong => {
+            println!("healthy");
+            Ok(())
+        }
+        other => anyhow::bail!("Expected pong, got {}", other.name()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use calloop::EventLoop;
+    use std::{collections::HashMap, env, path::PathBuf};
+
+    /// Runs `health` against an instance listening on `path`, dispatching its events meanwhile.
+    fn check_health(path: &Path) -> Result<()> {
+        let mut evl = EventLoop::try_new().unwrap();
+        let mut state = State::new(&evl, Config::default(), HashMap::new()).unwrap();
+        setup_listener(&mut state, path).unwrap();
+        let path = path.to_path_buf();
+        let client = std::thread::spawn(move || health(&path));
+        while !client.is_finished() {
+            evl.dispatch(Duration::from_millis(10), &mut state).unwrap();
+        }
+        client.join().unwrap()
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("cosmic-alt-test-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn health_pings_running_instance() {
+        let path = socket_path("control");
+        check_health(&path).unwrap();
+        // the socket of the instance gone with the loop is stale now
+        check_health(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(health(&path).is_err());
+    }
+
+    #[test]
+    fn health_pings_instance_on_abstract_socket() {
+        let name = format!("@cosmic-alt-test-{}-control", std::process::id());
+        let path = Path::new(&name);
+        check_health(path).unwrap();
+        // the name is released along with the listener
+        check_health(path).unwrap();
+        assert!(health(path).is_err());
+    }
+}
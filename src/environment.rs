@@ -0,0 +1,342 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{cli::Compositor, config::Config};
+
+/// Variables forwarded to cosmic-session as is, if they are set.
+const FORWARDED_VARIABLES: &[&str] = &[
+    "DISPLAY",
+    // session services
+    "DBUS_SESSION_BUS_ADDRESS",
+    "XDG_SESSION_TYPE",
+    // media servers for screencasting and audio
+    "PIPEWIRE_REMOTE",
+    "PIPEWIRE_RUNTIME_DIR",
+    "PULSE_SERVER",
+    // toolkit theming, so privileged settings panels and portals match the session
+    "GTK_THEME",
+    "QT_QPA_PLATFORM",
+    "QT_QPA_PLATFORMTHEME",
+    "XDG_CURRENT_DESKTOP",
+    "XCURSOR_THEME",
+    "XCURSOR_SIZE",
+];
+
+/// Locale of the session, forwarded if they are set. `PREFIX_*` matches a prefix.
+const LOCALE_VARIABLES: &[&str] = &["LANG", "LANGUAGE", "LC_*"];
+
+/// Variables never forwarded, no matter where they come from. `PREFIX_*` matches a prefix.
+///
+/// These would be injected into every process cosmic-session spawns.
+const DENIED_VARIABLES: &[&str] = &["LD_PRELOAD", "LD_LIBRARY_PATH", "LD_AUDIT", "DYLD_*"];
+
+/// Inherited variables only meaningful to us, which `forward_all_env` skips.
+const ALL_ENV_DENYLIST: &[&str] = &[
+    "WAYLAND_SOCKET",
+    "COSMIC_SESSION_SOCK",
+    "NOTIFY_SOCKET",
+    "WATCHDOG_PID",
+    "WATCHDOG_USEC",
+    "LISTEN_PID",
+    "LISTEN_FDS",
+    "LISTEN_FDNAMES",
+];
+
+/// Ipc sockets of the compositors we might be running on, forwarded if they are set.
+const COMPOSITOR_VARIABLES: &[(Compositor, &str)] = &[
+    (Compositor::Sway, "SWAYSOCK"),
+    (Compositor::Niri, "NIRI_SOCKET"),
+    // hyprland's sockets live in `$XDG_RUNTIME_DIR/hypr/$HYPRLAND_INSTANCE_SIGNATURE`
+    (Compositor::Hyprland, "HYPRLAND_INSTANCE_SIGNATURE"),
+];
+
+/// Compositor we are running on, as far as its ipc variables tell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DetectedCompositor {
+    /// None of the ipc variables is set
+    Unknown,
+    Known(Compositor),
+    /// Several ipc variables are set, e.g. when running nested
+    Ambiguous(Vec<Compositor>),
+}
+
+impl fmt::Display for DetectedCompositor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |compositor: &Compositor| {
+            compositor
+                .to_possible_value()
+                .map(|value| value.get_name().to_string())
+                .unwrap_or_default()
+        };
+        match self {
+            DetectedCompositor::Unknown => write!(f, "unknown"),
+            DetectedCompositor::Known(compositor) => write!(f, "{}", name(compositor)),
+            DetectedCompositor::Ambiguous(compositors) => write!(
+                f,
+                "ambiguous ({})",
+                compositors.iter().map(name).collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+}
+
+/// Detects the compositor from the ipc variables `COSMIC_ALT_COMPOSITOR` selects between.
+pub fn detect_compositor() -> DetectedCompositor {
+    detect_compositor_with(|name| env::var_os(name).is_some())
+}
+
+/// Detects the compositor from the ipc variables `is_set` reports as set.
+fn detect_compositor_with(is_set: impl Fn(&str) -> bool) -> DetectedCompositor {
+    let mut found = COMPOSITOR_VARIABLES
+        .iter()
+        .filter(|(_, name)| is_set(name))
+        .map(|(compositor, _)| *compositor)
+        .collect::<Vec<_>>();
+    match found.len() {
+        0 => DetectedCompositor::Unknown,
+        1 => DetectedCompositor::Known(found.remove(0)),
+        _ => DetectedCompositor::Ambiguous(found),
+    }
+}
+
+/// Collects the environment to send to cosmic-session.
+pub fn get_env(
+    wayland_display: Option<&str>,
+    compositor: Compositor,
+    no_x11: bool,
+    config: &Config,
+) -> Result<HashMap<String, String>> {
+    let mut env = HashMap::new();
+    if config.forward_all_env {
+        // the curated variables below still take precedence
+        env.extend(unicode_vars().filter(|(name, _)| {
+            // our own configuration
+            !name.starts_with("COSMIC_ALT_") && !ALL_ENV_DENYLIST.contains(&name.as_str())
+        }));
+    }
+    env.insert(
+        String::from("WAYLAND_DISPLAY"),
+        wayland_display
+            .map(String::from)
+            .context("WAYLAND_DISPLAY must be set")?,
+    );
+    if let Some(runtime_dir) =
+        runtime_dir().and_then(|path| path.into_os_string().into_string().ok())
+    {
+        env.insert(String::from("XDG_RUNTIME_DIR"), runtime_dir);
+    }
+    let compositor_variables = COMPOSITOR_VARIABLES
+        .iter()
+        .filter(|(owner, _)| compositor == Compositor::Auto || *owner == compositor)
+        .map(|(_, name)| name);
+    for name in FORWARDED_VARIABLES.iter().chain(compositor_variables) {
+        if no_x11 && *name == "DISPLAY" {
+            continue;
+        }
+        if let Ok(var) = env::var(name) {
+            env.insert(String::from(*name), var);
+        }
+    }
+    // there is no fixed list of `LC_*` variables
+    env.extend(unicode_vars().filter(|(name, _)| {
+        LOCALE_VARIABLES
+            .iter()
+            .any(|pattern| matches_pattern(pattern, name))
+    }));
+    if no_x11 {
+        // might have been inherited by `forward_all_env`
+        env.remove("DISPLAY");
+        env.remove("XAUTHORITY");
+    }
+    if env.contains_key("DISPLAY") {
+        if let Some(xauthority) = xauthority() {
+            env.insert(String::from("XAUTHORITY"), xauthority);
+        }
+    }
+    for name in &config.forward_env {
+        if let Ok(var) = env::var(name) {
+            env.insert(name.clone(), var);
+        }
+    }
+    if let Some(path) = config.env_file.as_deref() {
+        for (name, value) in read_env_file(path)? {
+            if !config.env_file_override && env.contains_key(&name) {
+                debug!(name, "Variable from env file is already set, ignoring it");
+                continue;
+            }
+            env.insert(name, value);
+        }
+    }
+    env.retain(|name, _| {
+        let denied = DENIED_VARIABLES
+            .iter()
+            .copied()
+            .chain(config.env_deny.iter().map(String::as_str))
+            .any(|pattern| matches_pattern(pattern, name));
+        if denied {
+            debug!(name, "Not forwarding denied variable");
+        }
+        !denied
+    });
+    Ok(env)
+}
+
+/// Our environment, skipping variables that aren't valid unicode.
+fn unicode_vars() -> impl Iterator<Item = (String, String)> {
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+}
+
+/// Whether `name` is `pattern`, or starts with it, if `pattern` ends with `*`.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+/// Reads `KEY=VALUE` lines from `path`, skipping blank lines and `#` comments.
+fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read env file {}", path.display()))?;
+    parse_env_file(&content).with_context(|| format!("Failed to parse env file {}", path.display()))
+}
+
+fn parse_env_file(content: &str) -> Result<Vec<(String, String)>> {
+    let mut variables = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line
+            .split_once('=')
+            .with_context(|| format!("line {}: expected KEY=VALUE", number + 1))?;
+        let name = name.trim();
+        anyhow::ensure!(!name.is_empty(), "line {}: empty variable name", number + 1);
+        let value = value.trim();
+        // like systemd's EnvironmentFile, one level of quotes is removed
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+            .unwrap_or(value);
+        variables.push((name.to_string(), value.to_string()));
+    }
+    Ok(variables)
+}
+
+/// `XAUTHORITY`, defaulting to `$HOME/.Xauthority` like Xlib does.
+fn xauthority() -> Option<String> {
+    env::var("XAUTHORITY").ok().or_else(|| {
+        let home = env::var("HOME").ok()?;
+        Some(format!("{}/.Xauthority", home.trim_end_matches('/')))
+    })
+}
+
+/// `XDG_RUNTIME_DIR`, if it is set to an absolute path.
+///
+/// Falls back to `/run/user/$UID`, where systemd-logind creates it, if that exists.
+pub fn runtime_dir() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+    {
+        return Some(path);
+    }
+
+    let fallback = fallback_runtime_dir();
+    if !fallback.is_dir() {
+        return None;
+    }
+    info!(path = %fallback.display(), "XDG_RUNTIME_DIR is not set or relative, using fallback");
+    Some(fallback)
+}
+
+/// Directory relative wayland sockets of the upstream compositor are looked up in.
+///
+/// `COSMIC_ALT_RUNTIME_DIR` takes precedence over [`runtime_dir`], e.g. if we run in a different
+/// mount namespace than the compositor. Unlike `XDG_RUNTIME_DIR` it isn't forwarded to the session.
+pub fn upstream_runtime_dir() -> Option<PathBuf> {
+    match env::var_os("COSMIC_ALT_RUNTIME_DIR").map(PathBuf::from) {
+        Some(path) if path.is_absolute() => Some(path),
+        Some(path) => {
+            warn!(path = %path.display(), "Ignoring relative COSMIC_ALT_RUNTIME_DIR");
+            runtime_dir()
+        }
+        None => runtime_dir(),
+    }
+}
+
+/// Default location of the runtime directory of the current user.
+pub fn fallback_runtime_dir() -> PathBuf {
+    PathBuf::from(format!("/run/user/{}", rustix::process::getuid().as_raw()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_env_file_skips_comments_and_strips_quotes() {
+        let content = "# comment\n\nFOO=bar\n  SPACED = value  \nDOUBLE=\"quoted value\"\nSINGLE='x'\nEMPTY=\nEQ=a=b\n";
+        assert_eq!(
+            parse_env_file(content).unwrap(),
+            [
+                ("FOO", "bar"),
+                ("SPACED", "value"),
+                ("DOUBLE", "quoted value"),
+                ("SINGLE", "x"),
+                ("EMPTY", ""),
+                ("EQ", "a=b"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_env_file_rejects_invalid_lines() {
+        assert!(parse_env_file("FOO=bar\nNOT A VARIABLE\n").is_err());
+        assert!(parse_env_file("=value\n").is_err());
+    }
+
+    #[test]
+    fn get_env_requires_wayland_display() {
+        assert!(get_env(None, Compositor::Auto, false, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn detect_compositor_from_ipc_variables() {
+        let detect = |set: &[&str]| detect_compositor_with(|name| set.contains(&name));
+        assert_eq!(detect(&[]), DetectedCompositor::Unknown);
+        assert_eq!(detect(&["DISPLAY"]), DetectedCompositor::Unknown);
+        assert_eq!(
+            detect(&["NIRI_SOCKET"]),
+            DetectedCompositor::Known(Compositor::Niri)
+        );
+        let nested = detect(&["HYPRLAND_INSTANCE_SIGNATURE", "SWAYSOCK"]);
+        assert_eq!(
+            nested,
+            DetectedCompositor::Ambiguous(vec![Compositor::Sway, Compositor::Hyprland])
+        );
+        assert_eq!(nested.to_string(), "ambiguous (sway, hyprland)");
+    }
+
+    #[test]
+    fn matches_pattern_exact_and_prefix() {
+        assert!(matches_pattern("LD_PRELOAD", "LD_PRELOAD"));
+        assert!(!matches_pattern("LD_PRELOAD", "LD_PRELOAD_EXTRA"));
+        assert!(matches_pattern("DYLD_*", "DYLD_INSERT_LIBRARIES"));
+        assert!(matches_pattern("LC_*", "LC_"));
+        assert!(!matches_pattern("LC_*", "LANG"));
+        assert!(matches_pattern("*", "ANYTHING"));
+    }
+}
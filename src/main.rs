@@ -1,24 +1,329 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use anyhow::Context;
-use calloop::{EventLoop, LoopHandle};
+use calloop::{
+    ping::{self, Ping},
+    signals::{Signal, Signals},
+    timer::{TimeoutAction, Timer},
+    EventLoop, LoopHandle, LoopSignal, RegistrationToken,
+};
+use clap::Parser;
+use sd_notify::NotifyState;
+use std::{
+    collections::HashMap,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, EnvFilter};
 
+use crate::{
+    cli::{Args, LogFormat, LogTarget, Upstream},
+    config::Config,
+};
+
+mod check;
+mod cli;
+mod config;
+mod control;
+mod environment;
+mod metrics;
+mod proxy;
 mod session;
+mod socket;
+mod spawn;
 
 pub struct State {
     loop_handle: LoopHandle<'static, Self>,
+    loop_signal: LoopSignal,
+    shutdown_ping: Ping,
+    shutting_down: bool,
+    connections: HashMap<u64, proxy::Connection>,
+    /// Privileged clients we spawned, by pid
+    children: HashMap<u32, spawn::SpawnedClient>,
+    next_connection_id: u64,
+    config: Config,
+    metrics: metrics::Metrics,
+    proxy_buffer: Vec<u8>,
+    /// Unless splicing is enabled, every chunk is copied through `proxy_buffer`
+    splice_pipe: Option<proxy::SplicePipe>,
+    wayland_display: Option<String>,
+    upstream: Vec<Upstream>,
+    /// Resolved on first use, see [`State::invalidate_upstream_address`]
+    upstream_address: Option<session::UpstreamAddress>,
+    /// Resolved along with `upstream_address`, but kept while no upstream can be reached.
+    /// `Some(None)` if there is none, so that isn't looked up again either.
+    upstream_runtime_dir: Option<Option<PathBuf>>,
+    wayland_socket: Option<UnixStream>,
+    session_protocol_version: Option<u32>,
+    session_socket: Option<UnixStream>,
+    /// Messages not yet written to the session socket
+    session_queue: Vec<u8>,
+    /// Bytes of our `hello` and `set_env` at the front of `session_queue`
+    handshake_pending: usize,
+    session_writer: Option<RegistrationToken>,
+    session_token: Option<RegistrationToken>,
+    /// Sees every session message before the default handling
+    message_handler: Option<Box<dyn session::MessageHandler>>,
+    last_pong: Instant,
+    env: HashMap<String, String>,
+}
+
+impl State {
+    /// Creates the state for `evl`, listening for shutdown requests but not yet for anything else.
+    ///
+    /// Privileged clients are forwarded to `WAYLAND_DISPLAY` until the upstream fields are set.
+    fn new(
+        evl: &EventLoop<'static, State>,
+        config: Config,
+        env: HashMap<String, String>,
+    ) -> anyhow::Result<State> {
+        let (shutdown_ping, shutdown_source) =
+            ping::make_ping().context("Failed to create shutdown ping")?;
+        evl.handle()
+            .insert_source(shutdown_source, |_, _, state| state.shutdown())
+            .map_err(|err| err.error)
+            .context("Failed to init the shutdown source")?;
+        let splice_pipe = match config.splice.then(proxy::SplicePipe::new) {
+            Some(Ok(pipe)) => Some(pipe),
+            Some(Err(err)) => {
+                warn!(
+                    ?err,
+                    "Failed to create splice pipe, copying all proxied data"
+                );
+                None
+            }
+            None => None,
+        };
+        Ok(State {
+            loop_handle: evl.handle(),
+            loop_signal: evl.get_signal(),
+            shutdown_ping,
+            shutting_down: false,
+            connections: HashMap::new(),
+            children: HashMap::new(),
+            next_connection_id: 0,
+            config,
+            metrics: metrics::Metrics::default(),
+            proxy_buffer: Vec::new(),
+            splice_pipe,
+            wayland_display: None,
+            upstream: Vec::new(),
+            upstream_address: None,
+            upstream_runtime_dir: None,
+            wayland_socket: None,
+            session_protocol_version: None,
+            session_socket: None,
+            session_queue: Vec::new(),
+            handshake_pending: 0,
+            session_writer: None,
+            session_token: None,
+            message_handler: None,
+            last_pong: Instant::now(),
+            env,
+        })
+    }
+
+    /// Number of currently proxied privileged clients.
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Looks up the runtime directory relative upstream sockets are resolved in, unless it is cached.
+    fn fill_upstream_runtime_dir(&mut self) {
+        if self.upstream_runtime_dir.is_none() {
+            self.upstream_runtime_dir = Some(environment::upstream_runtime_dir());
+            self.metrics.upstream_lookups_total += 1;
+        }
+    }
+
+    /// The runtime directory filled in by [`State::fill_upstream_runtime_dir`].
+    fn upstream_runtime_dir(&self) -> Option<&Path> {
+        self.upstream_runtime_dir.as_ref()?.as_deref()
+    }
+
+    /// Forgets the cached upstream address, so the next privileged client resolves it again.
+    fn invalidate_upstream_address(&mut self) {
+        self.upstream_address = None;
+        self.upstream_runtime_dir = None;
+    }
+
+    /// Installs `handler` in front of the default handling of session messages, replacing any previous one.
+    pub fn set_message_handler(&mut self, handler: impl session::MessageHandler + 'static) {
+        self.message_handler = Some(Box::new(handler));
+    }
+
+    /// Shuts down once control returns to the event loop.
+    ///
+    /// This is the only way to exit, so every source can finish what it is doing first.
+    pub fn request_shutdown(&self) {
+        self.shutdown_ping.ping();
+    }
+
+    /// Closes all proxied connections and stops the event loop.
+    fn shutdown(&mut self) {
+        self.shutting_down = true;
+        for (_, connection) in self.connections.drain() {
+            connection.shutdown();
+        }
+        self.loop_signal.stop();
+    }
+}
+
+/// Installs the global tracing subscriber.
+fn setup_logging(args: &Args) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse_lossy(&args.log_level);
+
+    let journald_err = match args.log_target {
+        LogTarget::Journald => match tracing_journald::layer() {
+            Ok(layer) => {
+                tracing::subscriber::set_global_default(
+                    tracing_subscriber::registry().with(filter).with(layer),
+                )
+                .expect("setting tracing default failed");
+                return;
+            }
+            Err(err) => Some(err),
+        },
+        LogTarget::Stderr => None,
+    };
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match args.log_format {
+        LogFormat::Json => tracing::subscriber::set_global_default(builder.json().finish()),
+        LogFormat::Text => tracing::subscriber::set_global_default(builder.finish()),
+    }
+    .expect("setting tracing default failed");
+    if let Some(err) = journald_err {
+        warn!(
+            ?err,
+            "Failed to connect to journald, logging to stderr instead"
+        );
+    }
+}
+
+/// Pings the systemd watchdog in half of its configured interval, if it is enabled.
+fn setup_watchdog(state: &mut State) -> anyhow::Result<()> {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return Ok(());
+    }
+
+    let interval = Duration::from_micros(usec) / 2;
+    state
+        .loop_handle
+        .insert_source(Timer::from_duration(interval), move |_, _, _| {
+            if let Err(err) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                warn!(?err, "Failed to ping systemd watchdog");
+            }
+            TimeoutAction::ToDuration(interval)
+        })
+        .map_err(|err| err.error)
+        .context("Failed to init the watchdog timer")?;
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
-    tracing::subscriber::set_global_default(tracing_subscriber::FmtSubscriber::new())
-        .expect("setting tracing default failed");
+    let args = Args::parse();
+    setup_logging(&args);
+    if args.health {
+        let path = args
+            .control_sock
+            .as_deref()
+            .context("No control socket to ping")?;
+        return control::health(path);
+    }
+    info!(
+        version = env!("CARGO_PKG_VERSION"),
+        protocol_version = session::PROTOCOL_VERSION,
+        "Starting"
+    );
+    let mut config = Config::load().context("Failed to load config file")?;
+    config.merge_args(&args);
+    config.validate().context("Invalid configuration")?;
+    info!(
+        proxy_buffer_size = config.proxy_buffer_size,
+        "Effective proxy buffer size"
+    );
+    if args.check {
+        return check::run(&args, &config);
+    }
+    let env = environment::get_env(
+        args.wayland_display.as_deref(),
+        args.compositor,
+        args.no_x11,
+        &config,
+    )
+    .context("Failed to collect the session environment")?;
 
     let mut evl = EventLoop::<'static, State>::try_new().context("Failed to create event loop")?;
     let evlh = evl.handle();
-    let mut state = State {
-        loop_handle: evl.handle(),
+    let mut state = State::new(&evl, config, env)?;
+    state.wayland_display = args.wayland_display.clone();
+    state.upstream = args.upstream();
+    state.wayland_socket = session::take_wayland_socket();
+
+    let upstream = if state.wayland_socket.is_some() {
+        String::from("WAYLAND_SOCKET")
+    } else {
+        // the first privileged client reuses the lookup
+        state.fill_upstream_runtime_dir();
+        // only the first candidate, the others are tried if it doesn't accept connections
+        session::UpstreamAddress::resolve(
+            state.upstream.first(),
+            state.wayland_display.as_deref(),
+            state.upstream_runtime_dir(),
+        )
+        .map_or_else(
+            |err| format!("unresolved ({err})"),
+            |address| address.to_string(),
+        )
     };
-    session::setup_socket(evlh).context("Failed to connect to cosmic-session")?;
+    info!(
+        compositor = %environment::detect_compositor(),
+        %upstream,
+        "Detected host compositor"
+    );
+
+    let signals = Signals::new(&[Signal::SIGTERM, Signal::SIGINT])
+        .context("Failed to setup signal handling")?;
+    evlh.insert_source(signals, |event, _, state| {
+        info!(signal = ?event.signal(), "Shutting down");
+        state.request_shutdown();
+    })
+    .context("Failed to init the signal source")?;
+
+    spawn::setup_reaper(&mut state)?;
+    // clap requires the session socket, unless only stdin is read
+    if let Some(session_fd) = args.session_fd {
+        session::setup_socket(&mut state, session_fd)
+            .context("Failed to connect to cosmic-session")?;
+        if let Some(timeout) = state.config.handshake_timeout() {
+            session::setup_handshake_timeout(&mut state, timeout)?;
+        }
+    } else {
+        info!("COSMIC_SESSION_SOCK is not set, only reading stdin");
+    }
+    if args.stdin_control {
+        session::setup_stdin_control(&mut state)?;
+    }
+    if let Some(interval) = args.ping_interval {
+        session::setup_ping(&mut state, Duration::from_secs(interval))?;
+    }
+    if let Some(timeout) = state.config.idle_timeout() {
+        proxy::setup_idle_timeout(&mut state, timeout)?;
+    }
+    if let Some(path) = args.metrics_sock.as_deref() {
+        metrics::setup_listener(&mut state, path)?;
+    }
+    if let Some(path) = args.control_sock.as_deref() {
+        control::setup_listener(&mut state, path)?;
+    }
+    setup_watchdog(&mut state)?;
     evl.run(None, &mut state, |_| {})
         .context("Event loop terminated")
 }
@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::{Context, Result};
+use calloop::{generic::Generic, Interest, Mode, PostAction};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io::{ErrorKind, Write},
+    os::unix::net::UnixListener,
+    path::Path,
+};
+use tracing::{debug, warn};
+
+use crate::{socket, State};
+
+/// Counters exposed on the metrics socket.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub connections_total: u64,
+    pub forwarded_bytes_total: u64,
+    pub forwarded_fds_total: u64,
+    /// Privileged clients that closed their connection
+    pub client_closes_total: u64,
+    /// Privileged clients whose connection failed
+    pub client_errors_total: u64,
+    /// Lookups of the runtime directory relative upstream sockets are resolved in
+    pub upstream_lookups_total: u64,
+    /// Handled session messages by tag, also answered to `stats_request`
+    pub session_messages_total: BTreeMap<&'static str, u64>,
+}
+
+impl Metrics {
+    pub fn count_session_message(&mut self, name: &'static str) {
+        *self.session_messages_total.entry(name).or_default() += 1;
+    }
+
+    /// Handled session messages by tag, as reported in `stats_response`.
+    pub fn session_message_counts(&self) -> HashMap<String, u64> {
+        self.session_messages_total
+            .iter()
+            .map(|(name, count)| (name.to_string(), *count))
+            .collect()
+    }
+}
+
+/// Listens on `path` and writes the current counters to every connecting client.
+///
+/// The output uses the Prometheus text exposition format, so e.g.
+/// `socat - UNIX-CONNECT:$COSMIC_ALT_METRICS_SOCK` is enough to scrape it.
+pub fn setup_listener(state: &mut State, path: &Path) -> Result<()> {
+    socket::remove_stale_socket(path)
+        .with_context(|| format!("Failed to remove stale {}", path.display()))?;
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind metrics socket {}", path.display()))?;
+    listener
+        .set_nonblocking(true)
+        .context("Failed to make metrics socket non-blocking")?;
+
+    state
+        .loop_handle
+        .insert_source(
+            Generic::new(listener, Interest::READ, Mode::Level),
+            |_, listener, state| {
+                loop {
+                    let mut stream = match listener.accept() {
+                        Ok((stream, _)) => stream,
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                        Err(err) => {
+                            warn!(?err, "Failed to accept metrics client");
+                            break;
+                        }
+                    };
+                    // the report is far smaller than the socket buffer, so this doesn't block
+                    if let Err(err) = stream.write_all(render(state).as_bytes()) {
+                        debug!(?err, "Failed to write metrics");
+                    }
+                }
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|err| err.error)
+        .context("Failed to init the metrics source")?;
+    Ok(())
+}
+
+fn render(state: &State) -> String {
+    let metrics = &state.metrics;
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# TYPE cosmic_alt_connections gauge\ncosmic_alt_connections {}",
+        state.connection_count()
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE cosmic_alt_connections_total counter\ncosmic_alt_connections_total {}",
+        metrics.connections_total
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE cosmic_alt_forwarded_bytes_total counter\ncosmic_alt_forwarded_bytes_total {}",
+        metrics.forwarded_bytes_total
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE cosmic_alt_forwarded_fds_total counter\ncosmic_alt_forwarded_fds_total {}",
+        metrics.forwarded_fds_total
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE cosmic_alt_client_closes_total counter\ncosmic_alt_client_closes_total {}",
+        metrics.client_closes_total
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE cosmic_alt_client_errors_total counter\ncosmic_alt_client_errors_total {}",
+        metrics.client_errors_total
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE cosmic_alt_upstream_lookups_total counter\ncosmic_alt_upstream_lookups_total {}",
+        metrics.upstream_lookups_total
+    );
+    let _ = writeln!(out, "# TYPE cosmic_alt_session_messages_total counter");
+    for (name, count) in &metrics.session_messages_total {
+        let _ = writeln!(
+            out,
+            "cosmic_alt_session_messages_total{{message=\"{name}\"}} {count}"
+        );
+    }
+    out
+}
@@ -0,0 +1,1279 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::{Context, Result};
+use calloop::{
+    generic::Generic,
+    timer::{TimeoutAction, Timer},
+    Interest, Mode, PostAction, RegistrationToken,
+};
+use rustix::{
+    io::Errno,
+    net::{RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, UCred},
+    pipe::{PipeFlags, SpliceFlags},
+};
+use sendfd::SendWithFd;
+use std::{
+    io::{self, ErrorKind, IoSliceMut},
+    net::{Shutdown, TcpStream},
+    os::unix::{
+        io::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+        net::UnixStream,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, info_span, trace, warn, Span};
+
+use crate::{
+    config::Config,
+    session::{self, Message},
+    State,
+};
+
+/// Maximum amount of fds forwarded along a single proxied read, matches libwayland's limit.
+const MAX_FDS_PER_MESSAGE: usize = 28;
+
+/// One end of a proxied connection.
+#[derive(Debug)]
+pub enum Stream {
+    Unix(UnixStream),
+    /// A remote compositor, which can't receive any fds
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Both),
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        match self {
+            Stream::Unix(stream) => stream.set_nonblocking(true),
+            Stream::Tcp(stream) => stream.set_nonblocking(true),
+        }
+    }
+
+    fn send_with_fd(&self, buf: &[u8], fds: &[i32]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.send_with_fd(buf, fds),
+            Stream::Tcp(_) if !fds.is_empty() => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "fds can't be forwarded over tcp",
+            )),
+            Stream::Tcp(stream) => io::Write::write(&mut &*stream, buf),
+        }
+    }
+}
+
+impl AsFd for Stream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Stream::Unix(stream) => stream.as_fd(),
+            Stream::Tcp(stream) => stream.as_fd(),
+        }
+    }
+}
+
+/// Pipe to splice fd-less chunks through, shared by all connections like the proxy buffer.
+///
+/// It is always empty, once a chunk was forwarded.
+#[derive(Debug)]
+pub struct SplicePipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+/// Failed half of splicing a chunk.
+#[derive(Debug)]
+enum SpliceError {
+    Read(Errno),
+    Write(Errno),
+}
+
+impl SplicePipe {
+    /// Creates the pipe, which holds 64 KiB by default. Larger chunks just take several splices.
+    pub fn new() -> rustix::io::Result<SplicePipe> {
+        let (read, write) = rustix::pipe::pipe_with(PipeFlags::CLOEXEC | PipeFlags::NONBLOCK)?;
+        Ok(SplicePipe { read, write })
+    }
+
+    /// Moves up to `peeked.len()` bytes from `from` to `to` without copying them through userspace.
+    ///
+    /// `peeked` holds a copy of the data at the head of `from`, which must not carry any fds,
+    /// as splicing would drop them. Returns the amount of bytes read and written, the ones
+    /// `to` didn't accept remain in `peeked` for the caller to buffer.
+    fn forward(
+        &self,
+        from: &Stream,
+        to: &Stream,
+        peeked: &mut [u8],
+    ) -> Result<(usize, usize), SpliceError> {
+        let flags = SpliceFlags::MOVE | SpliceFlags::NONBLOCK;
+        let bytes = loop {
+            match rustix::pipe::splice(from, None, &self.write, None, peeked.len(), flags) {
+                Err(Errno::INTR) => {}
+                res => break res.map_err(SpliceError::Read)?,
+            }
+        };
+        let mut sent = 0;
+        while sent < bytes {
+            match rustix::pipe::splice(&self.read, None, to, None, bytes - sent, flags) {
+                Ok(0) => return Err(SpliceError::Write(Errno::PIPE)),
+                Ok(n) => sent += n,
+                Err(Errno::INTR) => {}
+                Err(Errno::AGAIN) => break,
+                Err(err) => return Err(SpliceError::Write(err)),
+            }
+        }
+        // the pipe holds exactly the unsent part of `peeked`, read it back to empty the pipe
+        let mut drained = sent;
+        while drained < bytes {
+            match rustix::io::read(&self.read, &mut peeked[drained..bytes]) {
+                Ok(n) => drained += n,
+                Err(Errno::INTR) => {}
+                Err(err) => return Err(SpliceError::Read(err)),
+            }
+        }
+        Ok((bytes, sent))
+    }
+}
+
+/// Both ends of a proxied privileged wayland connection.
+pub struct Connection {
+    client: Stream,
+    server: Stream,
+    client_to_server: Traffic,
+    server_to_client: Traffic,
+    last_activity: Instant,
+    /// Sent along with the client's first data, if `client_credentials` is enabled
+    client_credentials: Option<UCred>,
+}
+
+/// Amount of data forwarded in one direction.
+#[derive(Debug, Default)]
+struct Traffic {
+    bytes: u64,
+    fds: u64,
+    /// Chunks the data was forwarded in, fewer with a larger `proxy_buffer_size`
+    reads: u64,
+    /// Source reading this direction, disabled while `backlog` is pending
+    reader: Option<RegistrationToken>,
+    /// Data the other end didn't accept yet
+    backlog: Option<Backlog>,
+    rate_limit: Option<RateLimit>,
+    protocol_trace: Option<ProtocolTrace>,
+}
+
+impl Traffic {
+    fn new(config: &Config) -> Traffic {
+        Traffic {
+            rate_limit: config.rate_limit_bytes_per_sec.map(RateLimit::new),
+            protocol_trace: config.trace_protocol.then(ProtocolTrace::default),
+            ..Traffic::default()
+        }
+    }
+}
+
+/// Remainder of a chunk, that couldn't be written without blocking.
+///
+/// Reading stops until it is flushed, so there is at most one chunk buffered per direction.
+#[derive(Debug)]
+struct Backlog {
+    buf: Vec<u8>,
+    /// Fds that didn't go out along with the written part yet
+    fds: Vec<OwnedFd>,
+}
+
+/// Reassembles wayland message headers from the proxied byte stream to trace them.
+#[derive(Debug, Default)]
+struct ProtocolTrace {
+    header: [u8; 8],
+    header_len: usize,
+    /// Bytes of the current message's arguments not seen yet
+    remaining: usize,
+}
+
+impl ProtocolTrace {
+    fn feed(&mut self, direction: Direction, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.remaining > 0 {
+                let skip = self.remaining.min(bytes.len());
+                self.remaining -= skip;
+                bytes = &bytes[skip..];
+                continue;
+            }
+
+            let take = (self.header.len() - self.header_len).min(bytes.len());
+            self.header[self.header_len..self.header_len + take].copy_from_slice(&bytes[..take]);
+            self.header_len += take;
+            bytes = &bytes[take..];
+            if self.header_len < self.header.len() {
+                return;
+            }
+
+            // object id, followed by the message size in the upper and the opcode in the lower half
+            let object = u32::from_ne_bytes(self.header[..4].try_into().unwrap());
+            let word = u32::from_ne_bytes(self.header[4..].try_into().unwrap());
+            let (size, opcode) = (word >> 16, word & 0xffff);
+            trace!(?direction, object, opcode, size, "Wayland message");
+            self.header_len = 0;
+            self.remaining = (size as usize).saturating_sub(self.header.len());
+        }
+    }
+}
+
+/// Token bucket limiting the bytes forwarded per second, allowing bursts of up to one second.
+#[derive(Debug)]
+struct RateLimit {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    /// Whether a timer to resume forwarding is already pending
+    paused: bool,
+}
+
+impl RateLimit {
+    fn new(bytes_per_sec: u64) -> RateLimit {
+        RateLimit {
+            rate: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            last_refill: Instant::now(),
+            paused: false,
+        }
+    }
+
+    /// Refills the bucket, returning how long to wait if it is still exhausted.
+    fn throttle(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        (self.tokens <= 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+
+    /// Reads may overshoot the remaining budget, which is paid back before the next one.
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+impl Connection {
+    pub fn shutdown(&self) {
+        let _ = self.client.shutdown();
+        let _ = self.server.shutdown();
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Direction {
+    /// End of the connection this direction reads from.
+    fn source(self) -> Side {
+        match self {
+            Direction::ClientToServer => Side::Client,
+            Direction::ServerToClient => Side::Upstream,
+        }
+    }
+
+    /// End of the connection this direction writes to.
+    fn target(self) -> Side {
+        match self {
+            Direction::ClientToServer => Side::Upstream,
+            Direction::ServerToClient => Side::Client,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Client,
+    Upstream,
+}
+
+/// Starts forwarding traffic between a privileged `client` and the wayland `server`.
+///
+/// Both streams are expected to have CLOEXEC set, which std already does for sockets it creates.
+/// `id` has to be unique, the session is notified about the connection using it.
+pub fn insert_connection(
+    state: &mut State,
+    id: u64,
+    client: UnixStream,
+    server: Stream,
+) -> Result<()> {
+    let _span = client_span(id).entered();
+    let client = Stream::Unix(client);
+    // writes must not block the event loop, a full socket pauses reading the other end instead
+    client
+        .set_nonblocking()
+        .context("Failed to make client stream non-blocking")?;
+    server
+        .set_nonblocking()
+        .context("Failed to make server stream non-blocking")?;
+    if let Some(size) = state.config.socket_buffer_size {
+        set_buffer_size(Side::Client, &client, size);
+        set_buffer_size(Side::Upstream, &server, size);
+    }
+    if state.config.client_credentials {
+        // its peer credentials are cosmic-session's, which created the socketpair
+        if let Err(err) = rustix::net::sockopt::set_socket_passcred(&client, true) {
+            warn!(?err, "Failed to enable receiving client credentials");
+        }
+    }
+    let client_source = client
+        .try_clone()
+        .context("Failed to clone client stream")?;
+    let server_source = server
+        .try_clone()
+        .context("Failed to clone server stream")?;
+
+    // both directions are set up before the connection is registered, so a failure leaves nothing behind
+    let server_token = state
+        .loop_handle
+        .insert_source(
+            Generic::new(server_source, Interest::READ, Mode::Edge),
+            move |_, stream, state| {
+                let _span = client_span(id).entered();
+                Ok(forward(state, id, stream, Direction::ServerToClient))
+            },
+        )
+        .map_err(|err| err.error)
+        .context("Failed to insert server source")?;
+    let client_token = match state.loop_handle.insert_source(
+        Generic::new(client_source, Interest::READ, Mode::Edge),
+        move |_, stream, state| {
+            let _span = client_span(id).entered();
+            Ok(forward(state, id, stream, Direction::ClientToServer))
+        },
+    ) {
+        Ok(token) => token,
+        Err(err) => {
+            // drops the server source's clone of the stream, `client` and `server` are dropped on return
+            state.loop_handle.remove(server_token);
+            return Err(err.error).context("Failed to insert client source");
+        }
+    };
+
+    let mut client_to_server = Traffic::new(&state.config);
+    client_to_server.reader = Some(client_token);
+    let mut server_to_client = Traffic::new(&state.config);
+    server_to_client.reader = Some(server_token);
+    state.connections.insert(
+        id,
+        Connection {
+            client,
+            server,
+            client_to_server,
+            server_to_client,
+            last_activity: Instant::now(),
+            client_credentials: None,
+        },
+    );
+    state.metrics.connections_total += 1;
+    info!(
+        count = state.connection_count(),
+        "Privileged client connected"
+    );
+    session::notify_session(state, &Message::ClientConnected { id });
+
+    Ok(())
+}
+
+/// Span tagging every log line about the proxied connection `id` with its `client_id`.
+fn client_span(id: u64) -> Span {
+    info_span!("client", client_id = id)
+}
+
+/// Requests socket buffers of `size` bytes for `stream`, logging the sizes the kernel settled on.
+///
+/// Failing to do so isn't fatal, the connection still works with the default sizes.
+fn set_buffer_size(side: Side, stream: &Stream, size: usize) {
+    let result = rustix::net::sockopt::set_socket_recv_buffer_size(stream, size)
+        .and_then(|()| rustix::net::sockopt::set_socket_send_buffer_size(stream, size))
+        .and_then(|()| {
+            Ok((
+                rustix::net::sockopt::get_socket_recv_buffer_size(stream)?,
+                rustix::net::sockopt::get_socket_send_buffer_size(stream)?,
+            ))
+        });
+    match result {
+        // linux doubles the requested size for bookkeeping, but caps it at `net.core.[rw]mem_max`
+        Ok((recv, send)) if recv < size || send < size => debug!(
+            ?side,
+            requested = size,
+            recv,
+            send,
+            "Socket buffer sizes clamped by the kernel, see net.core.rmem_max and wmem_max"
+        ),
+        Ok((recv, send)) => debug!(?side, requested = size, recv, send, "Socket buffer sizes"),
+        Err(err) => warn!(?side, ?err, "Failed to set socket buffer sizes"),
+    }
+}
+
+/// Forwards everything currently readable from `from` to the other end of the connection.
+fn forward(state: &mut State, id: u64, from: &Stream, direction: Direction) -> PostAction {
+    // all connections share one buffer, as we only ever forward one chunk at a time
+    let mut buf = std::mem::take(&mut state.proxy_buffer);
+    buf.resize(state.config.proxy_buffer_size, 0);
+    let action = drain(state, id, from, direction, &mut buf);
+    state.proxy_buffer = buf;
+    action
+}
+
+/// The sources are edge-triggered, so `from` has to be drained until it would block.
+fn drain(
+    state: &mut State,
+    id: u64,
+    from: &Stream,
+    direction: Direction,
+    buf: &mut [u8],
+) -> PostAction {
+    loop {
+        let Some(connection) = state.connections.get_mut(&id) else {
+            // the other direction already tore down the connection
+            return PostAction::Remove;
+        };
+        let (to, traffic) = match direction {
+            Direction::ClientToServer => (&connection.server, &mut connection.client_to_server),
+            Direction::ServerToClient => (&connection.client, &mut connection.server_to_client),
+        };
+
+        if traffic.backlog.is_some() {
+            // e.g. resumed by the rate limit, the writer source continues once the backlog is flushed
+            return PostAction::Continue;
+        }
+        if let Some(limit) = traffic.rate_limit.as_mut() {
+            if let Some(delay) = limit.throttle() {
+                if !limit.paused {
+                    limit.paused = true;
+                    resume_after(state, id, direction, delay);
+                }
+                return PostAction::Continue;
+            }
+        }
+
+        // fd-less chunks are spliced, saving the copy back out of userspace
+        let mut credentials = None;
+        let spliceable = match state.splice_pipe {
+            Some(_) => match peek(from, buf, &mut credentials) {
+                Ok(spliceable) => spliceable,
+                Err(Errno::AGAIN) => return PostAction::Continue,
+                Err(Errno::INTR) => continue,
+                Err(err) => {
+                    disconnect(state, id, direction.source(), Some(err.into()));
+                    return PostAction::Remove;
+                }
+            },
+            None => None,
+        };
+        let mut fds = Vec::new();
+        let (bytes, sent, sent_fds) = match (state.splice_pipe.as_ref(), spliceable) {
+            (Some(pipe), Some(peeked)) => match pipe.forward(from, to, &mut buf[..peeked]) {
+                Ok((bytes, sent)) => (bytes, sent, 0),
+                Err(SpliceError::Read(err)) => {
+                    disconnect(state, id, direction.source(), Some(err.into()));
+                    return PostAction::Remove;
+                }
+                Err(SpliceError::Write(err)) => {
+                    disconnect(state, id, direction.target(), Some(err.into()));
+                    return PostAction::Remove;
+                }
+            },
+            _ => {
+                let bytes = match recv(from, buf, &mut fds, &mut credentials) {
+                    Ok(0) => {
+                        disconnect(state, id, direction.source(), None);
+                        return PostAction::Remove;
+                    }
+                    Ok(bytes) => bytes,
+                    Err(Errno::AGAIN) => return PostAction::Continue,
+                    Err(Errno::INTR) => continue,
+                    Err(err) => {
+                        disconnect(state, id, direction.source(), Some(err.into()));
+                        return PostAction::Remove;
+                    }
+                };
+                let (sent, sent_fds) = match send(to, &buf[..bytes], &fds) {
+                    Ok(sent) => sent,
+                    Err(err) if err.kind() == ErrorKind::Unsupported => {
+                        warn!(
+                            fds = fds.len(),
+                            "Privileged client tried to pass fds to a tcp upstream, disconnecting"
+                        );
+                        remove_connection(state, id);
+                        return PostAction::Remove;
+                    }
+                    Err(err) => {
+                        disconnect(state, id, direction.target(), Some(err));
+                        return PostAction::Remove;
+                    }
+                };
+                (bytes, sent, sent_fds)
+            }
+        };
+
+        traffic.bytes += bytes as u64;
+        traffic.fds += fds.len() as u64;
+        traffic.reads += 1;
+        if let Some(limit) = traffic.rate_limit.as_mut() {
+            limit.consume(bytes);
+        }
+        if let Some(protocol_trace) = traffic.protocol_trace.as_mut() {
+            protocol_trace.feed(direction, &buf[..bytes]);
+        }
+        connection.last_activity = Instant::now();
+        if let Some(credentials) = credentials.filter(|_| connection.client_credentials.is_none()) {
+            info!(
+                pid = credentials.pid.as_raw_nonzero().get(),
+                uid = credentials.uid.as_raw(),
+                gid = credentials.gid.as_raw(),
+                "Privileged client credentials"
+            );
+            connection.client_credentials = Some(credentials);
+        }
+        state.metrics.forwarded_bytes_total += bytes as u64;
+        state.metrics.forwarded_fds_total += fds.len() as u64;
+        trace!(?direction, bytes, fds = fds.len(), "Forwarded data");
+
+        if sent < bytes {
+            let mut fds = fds;
+            fds.drain(..sent_fds);
+            traffic.backlog = Some(Backlog {
+                buf: buf[sent..bytes].to_vec(),
+                fds,
+            });
+            let reader = traffic.reader;
+            pause(state, id, direction, reader);
+            return PostAction::Continue;
+        }
+    }
+}
+
+/// Stops reading `direction` until its backlog was flushed to the other end.
+fn pause(state: &mut State, id: u64, direction: Direction, reader: Option<RegistrationToken>) {
+    trace!(?direction, "Peer is not keeping up, pausing reads");
+    let Some(connection) = state.connections.get(&id) else {
+        return;
+    };
+    let to = match direction {
+        Direction::ClientToServer => &connection.server,
+        Direction::ServerToClient => &connection.client,
+    };
+    let writer = match to.try_clone() {
+        Ok(to) => Generic::new(to, Interest::WRITE, Mode::Level),
+        Err(err) => {
+            warn!(?err, "Failed to clone stream to flush backlog");
+            remove_connection(state, id);
+            return;
+        }
+    };
+    // takes effect once the reader's callback returns, if we are called from it
+    if let Some(reader) = reader {
+        if let Err(err) = state.loop_handle.disable(&reader) {
+            warn!(?err, "Failed to pause reading");
+        }
+    }
+    let res = state
+        .loop_handle
+        .insert_source(writer, move |_, to, state| {
+            let _span = client_span(id).entered();
+            Ok(flush(state, id, to, direction))
+        });
+    if let Err(err) = res {
+        warn!(err = ?err.error, "Failed to watch for the peer becoming writable");
+        remove_connection(state, id);
+    }
+}
+
+/// Writes the backlog of `direction` once `to` is writable, resuming reads after it is gone.
+fn flush(state: &mut State, id: u64, to: &Stream, direction: Direction) -> PostAction {
+    let Some(connection) = state.connections.get_mut(&id) else {
+        return PostAction::Remove;
+    };
+    let traffic = match direction {
+        Direction::ClientToServer => &mut connection.client_to_server,
+        Direction::ServerToClient => &mut connection.server_to_client,
+    };
+    let Some(backlog) = traffic.backlog.as_mut() else {
+        return PostAction::Remove;
+    };
+
+    match send(to, &backlog.buf, &backlog.fds) {
+        Ok((sent, sent_fds)) if sent < backlog.buf.len() => {
+            backlog.buf.drain(..sent);
+            backlog.fds.drain(..sent_fds);
+            PostAction::Continue
+        }
+        Ok(_) => {
+            traffic.backlog = None;
+            trace!(?direction, "Peer caught up, resuming reads");
+            // edge-triggered, so data that arrived in the meantime is reported right away
+            if let Some(reader) = traffic.reader {
+                if let Err(err) = state.loop_handle.enable(&reader) {
+                    warn!(?err, "Failed to resume reading");
+                    remove_connection(state, id);
+                }
+            }
+            PostAction::Remove
+        }
+        Err(err) => {
+            disconnect(state, id, direction.target(), Some(err));
+            PostAction::Remove
+        }
+    }
+}
+
+/// Tears down a connection after `side` closed it or failed.
+///
+/// Wayland connections carry state, which a fresh upstream connection wouldn't know about,
+/// so clients of a restarted compositor can't be reattached and have to reconnect themselves.
+fn disconnect(state: &mut State, id: u64, side: Side, err: Option<io::Error>) {
+    match (side, err) {
+        // a clean EOF is how clients normally go away
+        (Side::Client, None) => {
+            debug!("Privileged client closed");
+            state.metrics.client_closes_total += 1;
+        }
+        (Side::Client, Some(err)) => {
+            warn!(errno = err.raw_os_error(), ?err, "Privileged client error");
+            state.metrics.client_errors_total += 1;
+        }
+        (Side::Upstream, err) => {
+            warn!(
+                ?err,
+                "Upstream compositor gone, disconnecting privileged client"
+            );
+            // the compositor might come back on a different socket
+            state.invalidate_upstream_address();
+        }
+    }
+    remove_connection(state, id);
+}
+
+/// Continues forwarding a throttled direction of a connection after `delay`.
+///
+/// The sources are edge-triggered, so they won't fire again for data we left unread.
+fn resume_after(state: &mut State, id: u64, direction: Direction, delay: Duration) {
+    let res = state
+        .loop_handle
+        .insert_source(Timer::from_duration(delay), move |_, _, state| {
+            let _span = client_span(id).entered();
+            let Some(connection) = state.connections.get_mut(&id) else {
+                return TimeoutAction::Drop;
+            };
+            let (from, traffic) = match direction {
+                Direction::ClientToServer => (&connection.client, &mut connection.client_to_server),
+                Direction::ServerToClient => (&connection.server, &mut connection.server_to_client),
+            };
+            if let Some(limit) = traffic.rate_limit.as_mut() {
+                limit.paused = false;
+            }
+            match from.try_clone() {
+                Ok(from) => {
+                    forward(state, id, &from, direction);
+                }
+                Err(err) => {
+                    warn!(?err, "Failed to resume throttled client");
+                    remove_connection(state, id);
+                }
+            }
+            TimeoutAction::Drop
+        });
+    if let Err(err) = res {
+        warn!(err = ?err.error, "Failed to schedule resuming throttled client");
+        remove_connection(state, id);
+    }
+}
+
+/// Reads without blocking from `from`, appending any received fds to `fds`.
+///
+/// Credentials are only sent along, if `from` has SO_PASSCRED enabled.
+fn recv(
+    from: &Stream,
+    buf: &mut [u8],
+    fds: &mut Vec<OwnedFd>,
+    credentials: &mut Option<UCred>,
+) -> rustix::io::Result<usize> {
+    let mut space = [0; rustix::cmsg_space!(ScmRights(MAX_FDS_PER_MESSAGE), ScmCredentials(1))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+    let msg = rustix::net::recvmsg(
+        from,
+        &mut [IoSliceMut::new(buf)],
+        &mut control,
+        RecvFlags::DONTWAIT | RecvFlags::CMSG_CLOEXEC,
+    )?;
+    for message in control.drain() {
+        match message {
+            RecvAncillaryMessage::ScmRights(received) => fds.extend(received),
+            RecvAncillaryMessage::ScmCredentials(received) => *credentials = Some(received),
+            _ => {}
+        }
+    }
+    Ok(msg.bytes)
+}
+
+/// Peeks at the head of `from`, returning its size, if it can be spliced.
+///
+/// That is the case for data not carrying any fds, the end of the stream is left to [`recv`] as well.
+/// Credentials sent along are stored in `credentials`, splicing drops them like fds.
+fn peek(
+    from: &Stream,
+    buf: &mut [u8],
+    credentials: &mut Option<UCred>,
+) -> rustix::io::Result<Option<usize>> {
+    let mut space = [0; rustix::cmsg_space!(ScmRights(MAX_FDS_PER_MESSAGE), ScmCredentials(1))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+    let msg = rustix::net::recvmsg(
+        from,
+        &mut [IoSliceMut::new(buf)],
+        &mut control,
+        RecvFlags::DONTWAIT | RecvFlags::PEEK | RecvFlags::CMSG_CLOEXEC,
+    )?;
+    // peeking installs copies of the fds, which are closed right away. Chunks with more fds than
+    // fit into `space` still deliver some of them, so a truncated control message is caught as well.
+    let mut has_fds = false;
+    for message in control.drain() {
+        match message {
+            RecvAncillaryMessage::ScmCredentials(received) => *credentials = Some(received),
+            _ => has_fds = true,
+        }
+    }
+    Ok((msg.bytes > 0 && !has_fds).then_some(msg.bytes))
+}
+
+/// Writes as much of `buf` to `to` as possible without blocking, attaching `fds` to the first chunk.
+///
+/// If the kernel refuses that many fds in one message, they are split into batches sent along
+/// with a single byte each, so they still arrive before the data following them.
+/// Returns the amount of bytes and fds written. Our copies of `fds` are closed by the caller afterwards.
+fn send(to: &Stream, buf: &[u8], fds: &[OwnedFd]) -> io::Result<(usize, usize)> {
+    let raw_fds = fds.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+    let mut sent = 0;
+    let mut sent_fds = 0;
+    let mut batch = raw_fds.len();
+    while sent < buf.len() {
+        let fds = &raw_fds[sent_fds..raw_fds.len().min(sent_fds + batch)];
+        let chunk = if sent_fds + fds.len() < raw_fds.len() {
+            &buf[sent..sent + 1]
+        } else {
+            &buf[sent..]
+        };
+        match to.send_with_fd(chunk, fds) {
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(ref e) if fds.len() > 1 && is_too_many_fds(e) => {
+                batch = fds.len() / 2;
+                debug!(
+                    fds = fds.len(),
+                    batch, "Too many fds for one message, splitting them"
+                );
+            }
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                sent += n;
+                // The kernel attaches the control message to the first byte sent,
+                // so the fds are only transmitted once a send made progress.
+                sent_fds += fds.len();
+            }
+            Err(err) => return Err(err),
+        }
+        if sent == buf.len() && sent_fds < raw_fds.len() {
+            // every batch needs a byte to travel with
+            return Err(Errno::MSGSIZE.into());
+        }
+    }
+    Ok((sent, sent_fds))
+}
+
+/// Whether a send failed, because the control message carried more fds than the kernel accepts.
+fn is_too_many_fds(err: &io::Error) -> bool {
+    // the kernel reports exceeding SCM_MAX_FD with EINVAL
+    [Errno::MSGSIZE, Errno::INVAL]
+        .iter()
+        .any(|errno| err.raw_os_error() == Some(errno.raw_os_error()))
+}
+
+/// Periodically closes privileged clients that haven't sent or received anything for `timeout`.
+pub fn setup_idle_timeout(state: &mut State, timeout: Duration) -> Result<()> {
+    // checking twice per timeout keeps the overshoot below half of it
+    let interval = timeout / 2;
+    state
+        .loop_handle
+        .insert_source(Timer::from_duration(interval), move |_, _, state| {
+            let idle = state
+                .connections
+                .iter()
+                .filter(|(_, connection)| connection.last_activity.elapsed() >= timeout)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            for id in idle {
+                let _span = client_span(id).entered();
+                info!(?timeout, "Privileged client idle, disconnecting");
+                remove_connection(state, id);
+            }
+            TimeoutAction::ToDuration(interval)
+        })
+        .map_err(|err| err.error)
+        .context("Failed to init the idle timer")?;
+    Ok(())
+}
+
+/// Closes the connection `id`, if it still exists, and tells the session about it.
+pub fn remove_connection(state: &mut State, id: u64) {
+    if let Some(connection) = state.connections.remove(&id) {
+        // wakes up the source of the other direction, which then removes itself
+        connection.shutdown();
+        // paused sources wouldn't notice
+        for traffic in [&connection.client_to_server, &connection.server_to_client] {
+            if let (Some(reader), Some(_)) = (traffic.reader, &traffic.backlog) {
+                let _ = state.loop_handle.enable(&reader);
+            }
+        }
+        info!(
+            id,
+            count = state.connection_count(),
+            client_to_server_bytes = connection.client_to_server.bytes,
+            client_to_server_fds = connection.client_to_server.fds,
+            client_to_server_reads = connection.client_to_server.reads,
+            server_to_client_bytes = connection.server_to_client.bytes,
+            server_to_client_fds = connection.server_to_client.fds,
+            server_to_client_reads = connection.server_to_client.reads,
+            client_pid = connection
+                .client_credentials
+                .map(|credentials| credentials.pid.as_raw_nonzero().get()),
+            "Privileged client removed"
+        );
+        session::notify_session(state, &Message::ClientDisconnected { id });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use calloop::EventLoop;
+    use sendfd::RecvWithFd;
+    use std::{
+        collections::HashMap,
+        io::{Read, Write},
+        os::unix::io::FromRawFd,
+        sync::{Arc, Mutex},
+    };
+
+    /// A privileged client proxied from `client` to `server`, both ends held by the test.
+    struct Proxy {
+        evl: EventLoop<'static, State>,
+        state: State,
+        client: UnixStream,
+        server: UnixStream,
+    }
+
+    impl Proxy {
+        fn new(config: Config) -> Proxy {
+            let evl = EventLoop::try_new().unwrap();
+            let mut state = State::new(&evl, config, HashMap::new()).unwrap();
+            let (client, ours) = UnixStream::pair().unwrap();
+            let (server, upstream) = UnixStream::pair().unwrap();
+            insert_connection(&mut state, 0, ours, Stream::Unix(upstream)).unwrap();
+            for stream in [&client, &server] {
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+            }
+            Proxy {
+                evl,
+                state,
+                client,
+                server,
+            }
+        }
+
+        fn dispatch(&mut self) {
+            self.evl
+                .dispatch(Duration::from_millis(10), &mut self.state)
+                .unwrap();
+        }
+
+        /// Writes `payload` to the client end in the background.
+        fn send(&self, payload: Vec<u8>) -> std::thread::JoinHandle<()> {
+            let mut client = self.client.try_clone().unwrap();
+            std::thread::spawn(move || client.write_all(&payload).unwrap())
+        }
+
+        /// Reads `len` bytes from the server end, while the event loop forwards them.
+        fn receive(&mut self, len: usize) -> Vec<u8> {
+            let mut server = self.server.try_clone().unwrap();
+            let reader = std::thread::spawn(move || {
+                let mut received = vec![0; len];
+                server.read_exact(&mut received).unwrap();
+                received
+            });
+            while !reader.is_finished() {
+                self.dispatch();
+            }
+            reader.join().unwrap()
+        }
+
+        fn transfer(&mut self, payload: &[u8]) -> Vec<u8> {
+            let writer = self.send(payload.to_vec());
+            let received = self.receive(payload.len());
+            writer.join().unwrap();
+            received
+        }
+
+        fn traffic(&self, direction: Direction) -> &Traffic {
+            let connection = &self.state.connections[&0];
+            match direction {
+                Direction::ClientToServer => &connection.client_to_server,
+                Direction::ServerToClient => &connection.server_to_client,
+            }
+        }
+    }
+
+    /// `len` bytes that don't repeat within a chunk, so reordered or lost data is noticed.
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// A wayland message header followed by `args` bytes of arguments.
+    fn message(object: u32, opcode: u16, args: usize) -> Vec<u8> {
+        let size = (8 + args) as u32;
+        let mut bytes = object.to_ne_bytes().to_vec();
+        bytes.extend((size << 16 | opcode as u32).to_ne_bytes());
+        bytes.resize(size as usize, 0);
+        bytes
+    }
+
+    #[test]
+    fn large_buffer_needs_fewer_reads() {
+        let payload = payload(1024 * 1024);
+        let reads = |proxy_buffer_size| {
+            let mut proxy = Proxy::new(Config {
+                proxy_buffer_size,
+                ..Config::default()
+            });
+            assert!(proxy.transfer(&payload) == payload);
+            proxy.traffic(Direction::ClientToServer).reads
+        };
+        let small = reads(4096);
+        let large = reads(256 * 1024);
+        assert!(small >= 256);
+        assert!(
+            large < small / 4,
+            "{large} reads, {small} with a small buffer"
+        );
+    }
+
+    #[test]
+    fn forwards_a_megabyte_with_backpressure() {
+        let mut proxy = Proxy::new(Config {
+            socket_buffer_size: Some(4096),
+            ..Config::default()
+        });
+        let payload = payload(1024 * 1024);
+        let writer = proxy.send(payload.clone());
+        // nothing reads the server end yet, so forwarding to it comes up short
+        for _ in 0..100 {
+            if proxy.traffic(Direction::ClientToServer).backlog.is_some() {
+                break;
+            }
+            proxy.dispatch();
+        }
+        assert!(proxy.traffic(Direction::ClientToServer).backlog.is_some());
+        assert!(proxy.receive(payload.len()) == payload);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn pauses_reading_for_a_slow_reader() {
+        let mut proxy = Proxy::new(Config {
+            proxy_buffer_size: 4096,
+            socket_buffer_size: Some(4096),
+            ..Config::default()
+        });
+        let payload = payload(256 * 1024);
+        let writer = proxy.send(payload.clone());
+        for _ in 0..10 {
+            proxy.dispatch();
+        }
+        // at most one chunk is held back, the rest stays in the client's socket
+        let traffic = proxy.traffic(Direction::ClientToServer);
+        assert!(traffic.backlog.as_ref().unwrap().buf.len() <= 4096);
+        let forwarded = traffic.bytes;
+        assert!(forwarded < payload.len() as u64);
+        for _ in 0..10 {
+            proxy.dispatch();
+        }
+        assert_eq!(proxy.traffic(Direction::ClientToServer).bytes, forwarded);
+
+        let mut server = proxy.server.try_clone().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut received = vec![0; payload.len()];
+            for chunk in received.chunks_mut(16 * 1024) {
+                std::thread::sleep(Duration::from_millis(1));
+                server.read_exact(chunk).unwrap();
+            }
+            received == payload
+        });
+        while !reader.is_finished() {
+            proxy.dispatch();
+        }
+        assert!(reader.join().unwrap());
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn forwards_fds_once_across_short_writes() {
+        let mut proxy = Proxy::new(Config {
+            socket_buffer_size: Some(4096),
+            ..Config::default()
+        });
+        let payload = payload(64 * 1024);
+        let sockets = (0..10)
+            .map(|_| UnixStream::pair().unwrap().0)
+            .collect::<Vec<_>>();
+        let raw_fds = sockets.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+        proxy.client.send_with_fd(&payload, &raw_fds).unwrap();
+        proxy.dispatch();
+        // the server end holds less than the chunk the fds came with
+        let backlog = proxy
+            .traffic(Direction::ClientToServer)
+            .backlog
+            .as_ref()
+            .unwrap();
+        assert!(backlog.fds.is_empty());
+
+        let server = proxy.server.try_clone().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut fds = Vec::new();
+            while received.len() < payload.len() {
+                let mut buf = [0; 4096];
+                let mut chunk_fds = [-1; MAX_FDS_PER_MESSAGE];
+                let (bytes, count) = server.recv_with_fd(&mut buf, &mut chunk_fds).unwrap();
+                assert!(bytes > 0);
+                received.extend_from_slice(&buf[..bytes]);
+                // SAFETY: just received, nothing else owns them
+                fds.extend(
+                    chunk_fds[..count]
+                        .iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(*fd) }),
+                );
+            }
+            (received == payload, fds)
+        });
+        while !reader.is_finished() {
+            proxy.dispatch();
+        }
+        let (intact, fds) = reader.join().unwrap();
+        assert!(intact);
+        let inode = |fd: BorrowedFd<'_>| rustix::fs::fstat(fd).unwrap().st_ino;
+        assert_eq!(
+            fds.iter().map(|fd| inode(fd.as_fd())).collect::<Vec<_>>(),
+            sockets
+                .iter()
+                .map(|fd| inode(fd.as_fd()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn splices_data_and_still_forwards_fds() {
+        let mut proxy = Proxy::new(Config {
+            splice: true,
+            ..Config::default()
+        });
+        assert!(proxy.state.splice_pipe.is_some());
+        let payload = payload(1024 * 1024);
+        assert!(proxy.transfer(&payload) == payload);
+
+        let (socket, _) = UnixStream::pair().unwrap();
+        proxy
+            .client
+            .send_with_fd(b"fd", &[socket.as_raw_fd()])
+            .unwrap();
+        proxy.dispatch();
+        let mut buf = [0; 2];
+        let mut fds = [-1; 1];
+        assert_eq!(
+            proxy.server.recv_with_fd(&mut buf, &mut fds).unwrap(),
+            (2, 1)
+        );
+        assert_eq!(&buf, b"fd");
+        // SAFETY: just received, nothing else owns it
+        let received = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+        let inode = |fd: BorrowedFd<'_>| rustix::fs::fstat(fd).unwrap().st_ino;
+        assert_eq!(inode(received.as_fd()), inode(socket.as_fd()));
+        assert_eq!(proxy.traffic(Direction::ClientToServer).fds, 1);
+
+        // splicing picks up again after the chunk with fds
+        assert!(proxy.transfer(&payload) == payload);
+    }
+
+    #[test]
+    fn splits_fds_beyond_one_control_message() {
+        let (ours, peer) = UnixStream::pair().unwrap();
+        let (socket, _) = UnixStream::pair().unwrap();
+        // more than SCM_MAX_FD, which the kernel accepts in one message
+        let fds = (0..300)
+            .map(|_| rustix::io::dup(&socket).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(send(&Stream::Unix(ours), b"data", &fds).unwrap(), (4, 300));
+        drop(fds);
+
+        let mut received = Vec::new();
+        let mut batches = Vec::new();
+        while received.len() < 4 {
+            let mut buf = [0; 4];
+            let mut fds = [-1; 253];
+            let (bytes, count) = peer.recv_with_fd(&mut buf, &mut fds).unwrap();
+            received.extend_from_slice(&buf[..bytes]);
+            batches.push(count);
+            for fd in &fds[..count] {
+                // SAFETY: just received, nothing else owns it
+                drop(unsafe { OwnedFd::from_raw_fd(*fd) });
+            }
+        }
+        assert_eq!(received, b"data");
+        // split into batches, each arriving with a part of the data
+        assert!(batches.len() > 1);
+        assert!(batches.iter().all(|count| *count > 0));
+        assert_eq!(batches.iter().sum::<usize>(), 300);
+    }
+
+    #[test]
+    fn receives_client_credentials() {
+        let (mut client, ours) = UnixStream::pair().unwrap();
+        rustix::net::sockopt::set_socket_passcred(&ours, true).unwrap();
+        let ours = Stream::Unix(ours);
+        io::Write::write_all(&mut client, b"hello").unwrap();
+
+        let mut buf = [0; 16];
+        let mut credentials = None;
+        assert_eq!(peek(&ours, &mut buf, &mut credentials), Ok(Some(5)));
+        let peeked = credentials.take().unwrap();
+        assert_eq!(peeked.pid, rustix::process::getpid());
+        assert_eq!(peeked.uid, rustix::process::getuid());
+        assert_eq!(peeked.gid, rustix::process::getgid());
+
+        let mut fds = Vec::new();
+        assert_eq!(recv(&ours, &mut buf, &mut fds, &mut credentials), Ok(5));
+        assert_eq!(credentials, Some(peeked));
+        assert!(fds.is_empty());
+    }
+
+    #[test]
+    fn failed_insert_leaves_nothing_behind() {
+        let evl = EventLoop::try_new().unwrap();
+        let mut state = State::new(&evl, Config::default(), HashMap::new()).unwrap();
+        // epoll rejects regular files, so the client source is the one failing to insert
+        let path =
+            std::env::temp_dir().join(format!("cosmic-alt-test-{}-insert", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let client = UnixStream::from(OwnedFd::from(file));
+        let (mut server, upstream) = UnixStream::pair().unwrap();
+        assert!(insert_connection(&mut state, 0, client, Stream::Unix(upstream)).is_err());
+        assert_eq!(state.connection_count(), 0);
+        // neither the server source's copy of the upstream end nor the original is left open
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(server.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    /// Log output of a test, collected by a subscriber only active on its thread.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Closes the client end after `unread` was forwarded to it, returning the client metrics and logs.
+    fn close_client(unread: &[u8]) -> (u64, u64, String) {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let metrics = tracing::subscriber::with_default(subscriber, || {
+            let mut proxy = Proxy::new(Config::default());
+            proxy.server.write_all(unread).unwrap();
+            proxy.dispatch();
+            let Proxy {
+                mut evl,
+                mut state,
+                client,
+                server: _server,
+            } = proxy;
+            drop(client);
+            evl.dispatch(Duration::from_millis(10), &mut state).unwrap();
+            assert_eq!(state.connection_count(), 0);
+            (
+                state.metrics.client_closes_total,
+                state.metrics.client_errors_total,
+            )
+        });
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        (metrics.0, metrics.1, logs)
+    }
+
+    #[test]
+    fn counts_clean_client_close() {
+        let (closes, errors, logs) = close_client(b"");
+        assert_eq!((closes, errors), (1, 0));
+        assert!(logs.contains("Privileged client closed"));
+        assert!(!logs.contains("Privileged client error"));
+    }
+
+    #[test]
+    fn counts_client_reset() {
+        // closing with unread data resets the connection
+        let (closes, errors, logs) = close_client(b"unread");
+        assert_eq!((closes, errors), (0, 1));
+        assert!(logs.contains("Privileged client error"));
+        assert!(!logs.contains("Privileged client closed"));
+    }
+
+    #[test]
+    fn protocol_trace_follows_message_boundaries() {
+        let mut trace = ProtocolTrace::default();
+        let mut bytes = message(1, 0, 12);
+        bytes.extend(message(3, 2, 0));
+        trace.feed(Direction::ClientToServer, &bytes);
+        assert_eq!((trace.header_len, trace.remaining), (0, 0));
+    }
+
+    #[test]
+    fn protocol_trace_reassembles_split_headers() {
+        let mut trace = ProtocolTrace::default();
+        let bytes = message(1, 0, 4);
+        trace.feed(Direction::ServerToClient, &bytes[..3]);
+        assert_eq!((trace.header_len, trace.remaining), (3, 0));
+        trace.feed(Direction::ServerToClient, &bytes[3..10]);
+        assert_eq!((trace.header_len, trace.remaining), (0, 2));
+        trace.feed(Direction::ServerToClient, &bytes[10..]);
+        assert_eq!((trace.header_len, trace.remaining), (0, 0));
+    }
+}
@@ -1,31 +1,147 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use anyhow::{Context, Result};
-use calloop::{generic::Generic, Interest, LoopHandle, Mode, PostAction};
-use sendfd::{RecvWithFd, SendWithFd};
+use calloop::{
+    generic::Generic,
+    timer::{TimeoutAction, Timer},
+    Interest, Mode, PostAction,
+};
+use rustix::{
+    fs::FileType,
+    io::Errno,
+    net::{RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendFlags},
+};
+use sd_notify::NotifyState;
+use sendfd::RecvWithFd;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap, env, io::{ErrorKind, Read, Write}, os::unix::{
-        io::{AsFd, BorrowedFd, FromRawFd, RawFd},
-        net::UnixStream,
-    }, path::PathBuf
+    collections::HashMap,
+    env,
+    ffi::OsStr,
+    fmt,
+    io::{self, ErrorKind, IoSliceMut},
+    net::TcpStream,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+            net::{SocketAddr, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
-use tracing::{error, warn, info};
+use tracing::{debug, error, info, warn};
 
-use crate::State;
+use crate::{
+    cli::Upstream,
+    environment,
+    proxy::{self, Stream},
+    spawn, State,
+};
+
+/// Upper bound for a single framed message, larger length prefixes are considered garbage.
+pub const MAX_MESSAGE_LEN: u32 = 1024 * 1024;
+/// Version of the session protocol spoken by this implementation.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Most fds the kernel passes in a single message, `SCM_MAX_FD`.
+const MAX_FDS_PER_MESSAGE: usize = 253;
+/// Amount of ping intervals without a pong, after which the session is considered dead.
+const PING_TIMEOUT_INTERVALS: u32 = 3;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "message")]
 pub enum Message {
-    SetEnv { variables: HashMap<String, String> },
-    NewPrivilegedClient { count: usize },
+    Hello {
+        protocol_version: u32,
+    },
+    Ping,
+    Pong,
+    SetEnv {
+        variables: HashMap<String, String>,
+    },
+    UnsetEnv {
+        variables: Vec<String>,
+    },
+    NewPrivilegedClient {
+        count: usize,
+    },
+    Shutdown,
+    CloseClient {
+        id: u64,
+    },
+    ClientConnected {
+        id: u64,
+    },
+    ClientDisconnected {
+        id: u64,
+    },
+    ClientError {
+        reason: String,
+    },
+    SpawnPrivileged {
+        exec: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    StatsRequest,
+    StatsResponse {
+        counts: HashMap<String, u64>,
+    },
+}
+
+impl Message {
+    /// Name of the message as used in the `message` tag.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "hello",
+            Message::Ping => "ping",
+            Message::Pong => "pong",
+            Message::SetEnv { .. } => "set_env",
+            Message::UnsetEnv { .. } => "unset_env",
+            Message::NewPrivilegedClient { .. } => "new_privileged_client",
+            Message::Shutdown => "shutdown",
+            Message::CloseClient { .. } => "close_client",
+            Message::ClientConnected { .. } => "client_connected",
+            Message::ClientDisconnected { .. } => "client_disconnected",
+            Message::ClientError { .. } => "client_error",
+            Message::SpawnPrivileged { .. } => "spawn_privileged",
+            Message::StatsRequest => "stats_request",
+            Message::StatsResponse { .. } => "stats_response",
+        }
+    }
+}
+
+/// What a [`MessageHandler`] did with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerOutcome {
+    /// The message was handled, the default handling is skipped
+    Consumed,
+    /// The message is handled as usual
+    PassThrough,
+}
+
+/// Hook to intercept or extend the handling of session messages, e.g. in forks or tests.
+///
+/// It sees every message before the default handling does. The fds of a `new_privileged_client` are
+/// only received by the default handling, so consuming one leaves them unread on the socket.
+pub trait MessageHandler {
+    fn handle(&mut self, msg: &Message, state: &mut State) -> HandlerOutcome;
 }
 
 struct StreamWrapper {
     stream: UnixStream,
+    /// Length prefix of the next message, which might arrive in pieces
+    len: [u8; 4],
+    len_read: usize,
     buffer: Vec<u8>,
-    size: u16,
+    size: u32,
     read_bytes: usize,
+    /// Fds announced by the last `NewPrivilegedClient`, that haven't arrived yet
+    pending_fds: usize,
+    /// Whether this is the stdin control channel instead of the session socket
+    stdin: bool,
 }
 impl AsFd for StreamWrapper {
     fn as_fd(&self) -> BorrowedFd<'_> {
@@ -36,242 +152,1866 @@ impl From<UnixStream> for StreamWrapper {
     fn from(stream: UnixStream) -> StreamWrapper {
         StreamWrapper {
             stream,
+            len: [0; 4],
+            len_read: 0,
             buffer: Vec::new(),
             size: 0,
             read_bytes: 0,
+            pending_fds: 0,
+            stdin: false,
         }
     }
 }
 
-unsafe fn set_cloexec(fd: RawFd) -> rustix::io::Result<()> {
-    if fd == -1 {
-        return Err(rustix::io::Errno::BADF);
-    }
-    let fd = BorrowedFd::borrow_raw(fd);
+/// Sets CLOEXEC on `fd`, returning the flags it had before.
+fn set_cloexec(fd: BorrowedFd<'_>) -> rustix::io::Result<rustix::io::FdFlags> {
     let flags = rustix::io::fcntl_getfd(fd)?;
-    rustix::io::fcntl_setfd(fd, flags | rustix::io::FdFlags::CLOEXEC)
+    if !flags.contains(rustix::io::FdFlags::CLOEXEC) {
+        rustix::io::fcntl_setfd(fd, flags | rustix::io::FdFlags::CLOEXEC)?;
+    }
+    Ok(flags)
+}
+
+/// Checks whether `fd` refers to a socket, anything else can't be proxied.
+fn is_socket(fd: BorrowedFd<'_>) -> rustix::io::Result<bool> {
+    let stat = rustix::fs::fstat(fd)?;
+    Ok(rustix::fs::FileType::from_raw_mode(stat.st_mode) == rustix::fs::FileType::Socket)
+}
+
+/// Takes the already connected wayland socket handed to us via `WAYLAND_SOCKET`.
+///
+/// Like libwayland we consume the variable, as the connection can only be used by a single client.
+/// It takes precedence over `WAYLAND_DISPLAY` for the first privileged client.
+pub fn take_wayland_socket() -> Option<UnixStream> {
+    let var = env::var("WAYLAND_SOCKET").ok()?;
+    env::remove_var("WAYLAND_SOCKET");
+    let fd = match var.parse::<RawFd>() {
+        Ok(fd) if fd >= 0 => fd,
+        Ok(fd) => {
+            warn!(fd, "WAYLAND_SOCKET is no valid file descriptor");
+            return None;
+        }
+        Err(err) => {
+            warn!(?err, "WAYLAND_SOCKET is no valid file descriptor");
+            return None;
+        }
+    };
+
+    // SAFETY: only borrowed until we know it is open
+    match set_cloexec(unsafe { BorrowedFd::borrow_raw(fd) }) {
+        // SAFETY: handed to us by our parent, nothing else in this process uses it
+        Ok(_) => Some(UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) })),
+        // fcntl only fails for fds that aren't open, so there is nothing to close
+        Err(err) => {
+            warn!(?err, "Failed to setup WAYLAND_SOCKET");
+            None
+        }
+    }
+}
+
+/// Resolved address of the upstream wayland server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamAddress {
+    Path(PathBuf),
+    Abstract(Vec<u8>),
+    Tcp(String),
 }
 
-pub fn get_env() -> Result<HashMap<String, String>> {
-    let mut env = HashMap::new();
-    env.insert(
-        String::from("WAYLAND_DISPLAY"),
-        env::var("WAYLAND_DISPLAY").expect("No WAYLAND_DISPLAY"),
+impl UpstreamAddress {
+    /// Resolves the address privileged clients get forwarded to.
+    ///
+    /// `upstream` takes precedence over `wayland_display`, relative socket names are looked up
+    /// in `runtime_dir` like libwayland does.
+    pub fn resolve(
+        upstream: Option<&Upstream>,
+        wayland_display: Option<&str>,
+        runtime_dir: Option<&Path>,
+    ) -> io::Result<UpstreamAddress> {
+        let socket_name = match upstream {
+            Some(Upstream::Tcp(address)) => return Ok(UpstreamAddress::Tcp(address.clone())),
+            Some(Upstream::Unix(socket)) => Some(socket.as_str()),
+            None => wayland_display,
+        };
+        let socket_name = socket_name
+            .map(OsStr::new)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "WAYLAND_DISPLAY is not set"))?;
+        if let Some(name) = socket_name.as_bytes().strip_prefix(b"@") {
+            // abstract namespace socket, there is no path to resolve
+            return Ok(UpstreamAddress::Abstract(name.to_vec()));
+        }
+
+        resolve_wayland_socket(socket_name, runtime_dir).map(UpstreamAddress::Path)
+    }
+
+    pub fn connect(&self) -> io::Result<Stream> {
+        match self {
+            UpstreamAddress::Path(path) => UnixStream::connect(path).map(Stream::Unix),
+            UpstreamAddress::Abstract(name) => {
+                UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?).map(Stream::Unix)
+            }
+            UpstreamAddress::Tcp(address) => {
+                // blocks the event loop until the remote answered, like any other upstream connect
+                let stream = TcpStream::connect(address)?;
+                // wayland is latency sensitive and mostly consists of small messages
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+        }
+    }
+}
+
+impl fmt::Display for UpstreamAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamAddress::Path(path) => write!(f, "{}", path.display()),
+            UpstreamAddress::Abstract(name) => write!(f, "@{}", String::from_utf8_lossy(name)),
+            UpstreamAddress::Tcp(address) => write!(f, "tcp://{address}"),
+        }
+    }
+}
+
+/// Resolves a wayland socket name to its path, like libwayland does.
+///
+/// Absolute names are used as is, relative ones are looked up in `runtime_dir`,
+/// see [`environment::upstream_runtime_dir`].
+pub fn resolve_wayland_socket(
+    socket_name: &OsStr,
+    runtime_dir: Option<&Path>,
+) -> io::Result<PathBuf> {
+    if socket_name.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Wayland socket name is empty",
+        ));
+    }
+
+    let socket_name = Path::new(socket_name);
+    if socket_name.is_absolute() {
+        return Ok(socket_name.to_path_buf());
+    }
+    let runtime_dir = runtime_dir.ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "XDG_RUNTIME_DIR is not set or relative and {} doesn't exist, can't resolve wayland socket {}",
+                environment::fallback_runtime_dir().display(),
+                socket_name.display(),
+            ),
+        )
+    })?;
+    Ok(runtime_dir.join(socket_name))
+}
+
+/// Connects to the upstream wayland server.
+fn connect_upstream(state: &mut State) -> io::Result<Stream> {
+    if let Some(stream) = state.wayland_socket.take() {
+        return Ok(Stream::Unix(stream));
+    }
+
+    if let Some(address) = state.upstream_address.as_ref() {
+        return address.connect().inspect_err(|_| {
+            // the environment might have changed since, e.g. a restarted compositor using a new socket
+            state.invalidate_upstream_address();
+        });
+    }
+
+    // our own environment never changes, so a burst of clients doesn't look it up for every attempt
+    state.fill_upstream_runtime_dir();
+    let (address, stream) = connect_first(
+        &state.upstream,
+        state.wayland_display.as_deref(),
+        state.upstream_runtime_dir(),
+    )?;
+    state.upstream_address = Some(address);
+    Ok(stream)
+}
+
+/// Connects to the first of the `upstream` candidates accepting a connection.
+///
+/// Without any candidates `wayland_display` is used.
+pub fn connect_first(
+    upstream: &[Upstream],
+    wayland_display: Option<&str>,
+    runtime_dir: Option<&Path>,
+) -> io::Result<(UpstreamAddress, Stream)> {
+    let candidates = if upstream.is_empty() {
+        vec![None]
+    } else {
+        upstream.iter().map(Some).collect()
+    };
+
+    let mut last_err = None;
+    for candidate in &candidates {
+        let address = match UpstreamAddress::resolve(*candidate, wayland_display, runtime_dir) {
+            Ok(address) => address,
+            Err(err) => {
+                last_err = Some(err);
+                continue;
+            }
+        };
+        match address.connect() {
+            Ok(stream) => {
+                if candidates.len() > 1 {
+                    info!(%address, "Using upstream compositor");
+                }
+                return Ok((address, stream));
+            }
+            Err(err) => {
+                debug!(%address, ?err, "Failed to connect to upstream compositor");
+                last_err = Some(io::Error::new(err.kind(), format!("{address}: {err}")));
+            }
+        }
+    }
+    Err(last_err.expect("there is always at least one candidate"))
+}
+
+/// Connects a privileged client to the upstream wayland server, retrying with a backoff on failure.
+pub fn connect_client(state: &mut State, id: u64, client_stream: UnixStream, attempt: usize) {
+    let err = match connect_upstream(state) {
+        Ok(server_stream) => {
+            if let Err(err) = proxy::insert_connection(state, id, client_stream, server_stream) {
+                warn!(?err, "Failed to forward wayland connection");
+                notify_client_error(
+                    state,
+                    format!("Failed to forward wayland connection: {err:#}"),
+                );
+            }
+            return;
+        }
+        Err(err) => err,
+    };
+
+    let Some(delay) = state.config.connect_retry_delay(attempt) else {
+        warn!(?err, "Failed to connect to wayland socket");
+        notify_client_error(state, format!("Failed to connect to wayland socket: {err}"));
+        return;
+    };
+    debug!(
+        ?err,
+        ?delay,
+        "Failed to connect to wayland socket, retrying"
     );
-    if let Ok(var) = env::var("DISPLAY") {
-        env.insert(String::from("DISPLAY"), var);
+    let mut client_stream = Some(client_stream);
+    if let Err(err) =
+        state
+            .loop_handle
+            .insert_source(Timer::from_duration(delay), move |_, _, state| {
+                if let Some(client_stream) = client_stream.take() {
+                    connect_client(state, id, client_stream, attempt + 1);
+                }
+                TimeoutAction::Drop
+            })
+    {
+        warn!(err = ?err.error, "Failed to schedule wayland connection retry");
+        notify_client_error(
+            state,
+            format!("Failed to schedule wayland connection retry: {}", err.error),
+        );
     }
-    if let Ok(var) = env::var("SWAYSOCK") {
-        env.insert(String::from("SWAYSOCK"), var);
+}
+
+/// Errors setting up or talking to the session socket.
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("Invalid session socket fd {0}, it must not be negative")]
+    InvalidFd(RawFd),
+    #[error("Session socket fd {0} is not open")]
+    ClosedFd(RawFd, #[source] rustix::io::Errno),
+    #[error("Session socket fd {0} is not a socket")]
+    NotASocket(RawFd),
+    #[error("Failed to set CLOEXEC on the session socket")]
+    Cloexec(#[source] rustix::io::Errno),
+    #[error("Failed to read session socket peer credentials")]
+    PeerCredentials(#[source] rustix::io::Errno),
+    #[error("Session socket peer runs as uid {peer}, expected {expected}")]
+    PeerUid { peer: u32, expected: u32 },
+    #[error("Failed to encode message into json")]
+    Encode(#[source] serde_json::Error),
+    #[error("Message of {0} bytes exceeds maximum message length")]
+    MessageTooLong(usize),
+    #[error("Failed to write message")]
+    Write(#[source] io::Error),
+    #[error("Failed to clone session socket")]
+    Clone(#[source] io::Error),
+    #[error("Failed to init the cosmic session socket source")]
+    InsertSource(#[source] calloop::Error),
+}
+
+/// Encodes `message` into a frame, prefixed by its length.
+pub fn encode_message(message: &Message) -> Result<Vec<u8>, SessionError> {
+    let message = serde_json::to_string(message).map_err(SessionError::Encode)?;
+    let bytes = message.into_bytes();
+    let len = u32::try_from(bytes.len())
+        .ok()
+        .filter(|len| *len <= MAX_MESSAGE_LEN)
+        .ok_or(SessionError::MessageTooLong(bytes.len()))?
+        .to_ne_bytes();
+    let mut frame = Vec::with_capacity(len.len() + bytes.len());
+    frame.extend_from_slice(&len);
+    frame.extend_from_slice(&bytes);
+    Ok(frame)
+}
+
+/// Queues a length-prefixed `message` for the session socket and sends as much as possible right away.
+fn send_message(state: &mut State, message: &Message) -> Result<(), SessionError> {
+    let frame = encode_message(message)?;
+    state.session_queue.extend_from_slice(&frame);
+    if state.session_writer.is_some() {
+        // already waiting for the socket to become writable, keep the order
+        return Ok(());
     }
-    if let Ok(var) = env::var("NIRI_SOCKET") {
-        env.insert(String::from("NIRI_SOCKET"), var);
+    flush_session_queue(state)
+}
+
+/// Writes the queued messages without blocking.
+///
+/// Whatever doesn't fit into the socket buffer is sent by a write source once the session reads again,
+/// which removes itself after the queue ran empty. Having it separate from the level-triggered read
+/// source avoids waking up for an always writable socket.
+fn flush_session_queue(state: &mut State) -> Result<(), SessionError> {
+    let Some(socket) = state.session_socket.as_ref() else {
+        state.session_queue.clear();
+        state.handshake_pending = 0;
+        return Ok(());
+    };
+    while !state.session_queue.is_empty() {
+        match rustix::net::send(
+            socket,
+            &state.session_queue,
+            SendFlags::DONTWAIT | SendFlags::NOSIGNAL,
+        ) {
+            Ok(written) => {
+                state.session_queue.drain(..written);
+                state.handshake_pending = state.handshake_pending.saturating_sub(written);
+            }
+            Err(Errno::INTR) => {}
+            Err(Errno::AGAIN) => {
+                if state.session_writer.is_none() {
+                    let socket = socket.try_clone().map_err(SessionError::Clone)?;
+                    let token = state
+                        .loop_handle
+                        .insert_source(
+                            Generic::new(socket, Interest::WRITE, Mode::Level),
+                            |_, _, state| {
+                                if let Err(err) = flush_session_queue(state) {
+                                    warn!(?err, "Failed to write to session socket");
+                                    state.session_writer = None;
+                                    return Ok(PostAction::Remove);
+                                }
+                                if state.session_queue.is_empty() {
+                                    state.session_writer = None;
+                                    return Ok(PostAction::Remove);
+                                }
+                                Ok(PostAction::Continue)
+                            },
+                        )
+                        .map_err(|err| SessionError::InsertSource(err.error))?;
+                    state.session_writer = Some(token);
+                }
+                return Ok(());
+            }
+            Err(err) => return Err(SessionError::Write(err.into())),
+        }
     }
-    Ok(env)
+    Ok(())
 }
 
-pub fn setup_socket(handle: LoopHandle<State>) -> Result<()> {
-    let fd = std::env::var("COSMIC_SESSION_SOCK")
-        .context("Failed to find cosmic session socket")?
-        .parse::<RawFd>()
-        .context("COSMIC_SESSION_SOCK is no valid file descriptor")?;
+/// Sends `message` to cosmic-session, if we are still connected to it.
+pub fn notify_session(state: &mut State, message: &Message) {
+    if state.session_socket.is_none() {
+        return;
+    }
+    if let Err(err) = send_message(state, message) {
+        warn!(?err, ?message, "Failed to notify cosmic-session");
+    }
+}
 
-    let mut session_socket = match unsafe { set_cloexec(fd) } {
+/// Tells cosmic-session that a privileged client it requested could not be wired up.
+fn notify_client_error(state: &mut State, reason: String) {
+    notify_session(state, &Message::ClientError { reason });
+}
+
+/// Starts talking to cosmic-session over the inherited socket `fd`, taking ownership of it.
+pub fn setup_socket(state: &mut State, fd: RawFd) -> Result<(), SessionError> {
+    if fd < 0 {
+        return Err(SessionError::InvalidFd(fd));
+    }
+    // SAFETY: only borrowed until we know it is an open socket
+    match is_socket(unsafe { BorrowedFd::borrow_raw(fd) }) {
+        Ok(true) => {}
+        // not ours to close, it might be something else entirely
+        Ok(false) => return Err(SessionError::NotASocket(fd)),
+        Err(err) => return Err(SessionError::ClosedFd(fd, err)),
+    }
+    // SAFETY: an open socket handed to us by cosmic-session, dropping it closes it on every error path
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let session_socket = match set_cloexec(fd.as_fd()) {
         // CLOEXEC worked and we can startup with session IPC
-        Ok(_) => unsafe { UnixStream::from_raw_fd(fd) },
-        // CLOEXEC didn't work, something is wrong with the fd, just close it
+        Ok(flags) => {
+            // an fd without CLOEXEC would also have leaked into anything else our parent spawned
+            if flags.contains(rustix::io::FdFlags::CLOEXEC) {
+                debug!(
+                    fd = fd.as_raw_fd(),
+                    ?flags,
+                    "Session socket already had CLOEXEC set"
+                );
+            } else {
+                debug!(
+                    fd = fd.as_raw_fd(),
+                    ?flags,
+                    "Session socket was inherited without CLOEXEC, set it"
+                );
+            }
+            UnixStream::from(fd)
+        }
+        // CLOEXEC didn't work, something is wrong with the fd, dropping closes it
+        Err(err) => return Err(SessionError::Cloexec(err)),
+    };
+    setup_socket_from_stream(state, session_socket)
+}
+
+/// Starts talking to cosmic-session over an already connected `session_socket`.
+///
+/// The socket is expected to have CLOEXEC set.
+pub fn setup_socket_from_stream(
+    state: &mut State,
+    session_socket: UnixStream,
+) -> Result<(), SessionError> {
+    let creds = rustix::net::sockopt::get_socket_peercred(&session_socket)
+        .map_err(SessionError::PeerCredentials)?;
+    debug!(
+        pid = creds.pid.as_raw_nonzero().get(),
+        uid = creds.uid.as_raw(),
+        gid = creds.gid.as_raw(),
+        "Session socket peer credentials"
+    );
+    let uid = rustix::process::getuid();
+    if creds.uid != uid {
+        return Err(SessionError::PeerUid {
+            peer: creds.uid.as_raw(),
+            expected: uid.as_raw(),
+        });
+    }
+
+    let socket_clone = session_socket.try_clone().map_err(SessionError::Clone)?;
+    state.session_socket = Some(socket_clone);
+    // queued like any other message, an inherited non-blocking socket or a full buffer doesn't fail startup
+    send_message(
+        state,
+        &Message::Hello {
+            protocol_version: PROTOCOL_VERSION,
+        },
+    )?;
+    send_message(
+        state,
+        &Message::SetEnv {
+            variables: state.env.clone(),
+        },
+    )?;
+    // nothing else was queued before, so whatever is left of the queue belongs to the handshake
+    state.handshake_pending = state.session_queue.len();
+
+    let oneshot = state.config.session_oneshot;
+    let mode = if oneshot { Mode::OneShot } else { Mode::Level };
+    let token = state
+        .loop_handle
+        .insert_source(
+            Generic::new(StreamWrapper::from(session_socket), Interest::READ, mode),
+            move |_, stream, state| {
+                // SAFETY: We don't drop the stream!
+                let stream = unsafe { stream.get_mut() };
+                if oneshot {
+                    read_session_message(state, stream)
+                } else {
+                    read_session_socket(state, stream)
+                }
+            },
+        )
+        .map_err(|err| SessionError::InsertSource(err.error))?;
+    state.session_token = Some(token);
+
+    // no-op, if we aren't started by systemd
+    if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!(?err, "Failed to notify systemd about readiness");
+    }
+
+    Ok(())
+}
+
+/// Reads framed messages from stdin like from the session socket, for testing without cosmic-session.
+///
+/// They are handled like messages of the session, except for `new_privileged_client`, as no fds
+/// can be passed over stdin. Answers still go to the session socket, if there is one.
+/// Stdin is watched like the session socket, unless it is a regular file, which is read to its end at once.
+pub fn setup_stdin_control(state: &mut State) -> Result<()> {
+    let stdin = io::stdin()
+        .as_fd()
+        .try_clone_to_owned()
+        .context("Failed to duplicate stdin")?;
+    setup_stdin_control_from_fd(state, stdin)
+}
+
+/// Reads framed messages from `stdin` like [`setup_stdin_control`] does from our stdin.
+pub fn setup_stdin_control_from_fd(state: &mut State, stdin: OwnedFd) -> Result<()> {
+    let is_file = rustix::fs::fstat(&stdin)
+        .is_ok_and(|stat| FileType::from_raw_mode(stat.st_mode) == FileType::RegularFile);
+    // only ever read(2) from, which works for pipes as well
+    let mut stream = StreamWrapper::from(UnixStream::from(stdin));
+    stream.stdin = true;
+    if is_file {
+        // epoll rejects regular files, but reading them never blocks, so all of it is handled right away
+        info!("Reading control messages from the file on stdin");
+        while read_session_socket(state, &mut stream)? != PostAction::Remove {}
+        return Ok(());
+    }
+    state
+        .loop_handle
+        .insert_source(
+            Generic::new(stream, Interest::READ, Mode::Level),
+            |_, stream, state| {
+                // SAFETY: We don't drop the stream!
+                let stream = unsafe { stream.get_mut() };
+                read_session_socket(state, stream)
+            },
+        )
+        .map_err(|err| err.error)
+        .context("Failed to init the stdin control source")?;
+    info!("Reading control messages from stdin");
+    Ok(())
+}
+
+/// Reads from the session socket until a whole message was handled or nothing is left to read.
+///
+/// A one-shot source stays disarmed until this returns, so a message arriving at once costs a single
+/// wakeup instead of one per read. Whatever is still missing of a message re-arms it as well.
+fn read_session_message(state: &mut State, stream: &mut StreamWrapper) -> io::Result<PostAction> {
+    loop {
+        let progress = |stream: &StreamWrapper| {
+            (
+                stream.len_read,
+                stream.size,
+                stream.read_bytes,
+                stream.pending_fds,
+            )
+        };
+        let before = progress(stream);
+        let action = read_session_socket(state, stream)?;
+        if action != PostAction::Continue {
+            return Ok(action);
+        }
+        let handled = stream.len_read == 0 && stream.size == 0 && stream.pending_fds == 0;
+        if handled || progress(stream) == before {
+            return Ok(PostAction::Reregister);
+        }
+    }
+}
+
+/// Reads the next piece of a message from the session socket and handles it once complete.
+///
+/// Each call reads at most once, the source calls it again while the socket stays readable.
+/// Reads never wait for data that isn't there yet, which a peer slow to send the rest would stall us on.
+fn read_session_socket(state: &mut State, stream: &mut StreamWrapper) -> io::Result<PostAction> {
+    if stream.pending_fds > 0 && stream.size == 0 && stream.len_read == 0 {
+        state.recv_pending_fds(stream);
+        if stream.pending_fds > 0 {
+            return Ok(PostAction::Continue);
+        }
+    }
+
+    if stream.size == 0 {
+        stream.len_read += match read_stream(
+            &stream.stream,
+            stream.stdin,
+            &mut stream.len[stream.len_read..],
+        ) {
+            Ok(0) => return Ok(stream_closed(state, stream)),
+            Ok(read) => read,
+            // a reset happens, if the session closes with our messages still unread
+            Err(err) if err.kind() == ErrorKind::ConnectionReset => {
+                return Ok(stream_closed(state, stream))
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                return Ok(PostAction::Continue);
+            }
+            Err(err) => {
+                warn!(?err, "Error reading from session socket");
+                return Ok(PostAction::Remove);
+            }
+        };
+        if stream.len_read < stream.len.len() {
+            return Ok(PostAction::Continue);
+        }
+        stream.len_read = 0;
+
+        let size = u32::from_ne_bytes(stream.len);
+        // checked before allocating the body, the prefix comes straight from the peer
+        if size > MAX_MESSAGE_LEN {
+            error!(
+                size,
+                max = MAX_MESSAGE_LEN,
+                stdin = stream.stdin,
+                "Session message exceeds maximum message length, closing session socket"
+            );
+            // the framing is lost, like for a closed socket only stdin can go away on its own
+            return Ok(stream_closed(state, stream));
+        }
+        if size == 0 {
+            // there is no body to wait for, an empty read would look like a closed socket
+            warn!("Ignoring empty session message");
+            return Ok(PostAction::Continue);
+        }
+        stream.size = size;
+        stream.buffer = vec![0; stream.size as usize];
+        return Ok(PostAction::Continue);
+    }
+
+    // never past the end of this frame, the next one stays in the socket for the next iteration
+    let body = &mut stream.buffer[stream.read_bytes..stream.size as usize];
+    stream.read_bytes += match read_stream(&stream.stream, stream.stdin, body) {
+        Ok(0) => return Ok(stream_closed(state, stream)),
+        Ok(size) => size,
+        Err(err) if err.kind() == ErrorKind::ConnectionReset => {
+            return Ok(stream_closed(state, stream))
+        }
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+            return Ok(PostAction::Continue);
+        }
         Err(err) => {
-            unsafe { rustix::io::close(fd) };
-            return Err(err).with_context(|| "Failed to setup session socket");
+            error!(?err, "Error reading from session socket");
+            return Ok(PostAction::Remove);
         }
     };
 
-    let env = get_env()?;
-    let message = serde_json::to_string(&Message::SetEnv { variables: env })
-        .with_context(|| "Failed to encode environment variables into json")?;
-    let bytes = message.into_bytes();
-    let len = (bytes.len() as u16).to_ne_bytes();
-    session_socket
-        .write_all(&len)
-        .with_context(|| "Failed to write message len")?;
-    session_socket
-        .write_all(&bytes)
-        .with_context(|| "Failed to write message bytes")?;
-
-    handle.insert_source(
-        Generic::new(StreamWrapper::from(session_socket), Interest::READ, Mode::Level),
-        move |_, stream, state| {
-            // SAFETY: We don't drop the stream!
-            let stream = unsafe { stream.get_mut() };
-
-            if stream.size == 0 {
-                let mut len = [0u8; 2];
-                match stream.stream.read_exact(&mut len) {
-                    Ok(()) => {
-                        stream.size = u16::from_ne_bytes(len);
-                        stream.buffer = vec![0; stream.size as usize];
-                    },
-                    Err(err) => {
-                        warn!(?err, "Error reading from session socket");
-                        return Ok(PostAction::Remove);
+    if stream.read_bytes != 0 && stream.read_bytes == stream.size as usize {
+        stream.size = 0;
+        stream.read_bytes = 0;
+        match std::str::from_utf8(&stream.buffer) {
+            Ok(message) => match serde_json::from_str::<'_, Message>(message) {
+                Ok(parsed) => {
+                    let name = parsed.name();
+                    match state.handle_message(parsed, stream) {
+                        Ok(action) => Ok(action),
+                        Err(err) => {
+                            warn!(tag = name, ?err, "Failed to handle session socket message");
+                            Ok(PostAction::Continue)
+                        }
                     }
                 }
+                // unknown tags end up here as well, so name the tag to tell them apart
+                Err(err) => {
+                    warn!(tag = message_tag(message), ?err, "Unknown or invalid session socket message, are you using incompatible cosmic-session and cosmic-comp versions?");
+                    Ok(PostAction::Continue)
+                }
+            },
+            Err(err) => {
+                warn!(?err, "Invalid message from session sock");
+                Ok(PostAction::Continue)
             }
+        }
+    } else {
+        Ok(PostAction::Continue)
+    }
+}
 
-            stream.read_bytes += match stream.stream.read(&mut stream.buffer) {
-                Ok(size) => size,
+/// Reads from the session socket without blocking, or from stdin.
+///
+/// Stdin might not be a socket, so it is read(2) from instead. It is only read while readable.
+fn read_stream(stream: &UnixStream, stdin: bool, buf: &mut [u8]) -> io::Result<usize> {
+    if stdin {
+        rustix::io::read(stream, buf)
+    } else {
+        rustix::net::recv(stream, buf, RecvFlags::DONTWAIT)
+    }
+    .map_err(io::Error::from)
+}
+
+impl State {
+    /// Acts on a message received from cosmic-session, returning the action for the session source.
+    ///
+    /// `stream` is the session socket, for messages carrying fds along with them, or stdin.
+    fn handle_message(
+        &mut self,
+        message: Message,
+        stream: &mut StreamWrapper,
+    ) -> Result<PostAction> {
+        self.metrics.count_session_message(message.name());
+        // taken out for the call, so the handler gets the rest of the state
+        if let Some(mut handler) = self.message_handler.take() {
+            let outcome = handler.handle(&message, self);
+            self.message_handler = Some(handler);
+            if outcome == HandlerOutcome::Consumed {
+                debug!(
+                    tag = message.name(),
+                    "Session message consumed by custom handler"
+                );
+                return Ok(PostAction::Continue);
+            }
+        }
+        match message {
+            Message::Hello { protocol_version } => self.handle_hello(protocol_version),
+            Message::Ping => self.handle_ping()?,
+            Message::Pong => self.last_pong = Instant::now(),
+            Message::SetEnv { variables } => self.handle_set_env(variables),
+            Message::UnsetEnv { variables } => self.handle_unset_env(variables),
+            Message::NewPrivilegedClient { .. } if stream.stdin => {
+                anyhow::bail!(
+                    "Fds can't be passed over stdin, privileged clients need the session socket"
+                )
+            }
+            Message::NewPrivilegedClient { count } => {
+                self.handle_new_privileged_client(count, stream)
+            }
+            Message::SpawnPrivileged { exec, args } => self.handle_spawn_privileged(&exec, &args),
+            Message::CloseClient { id } => self.handle_close_client(id),
+            Message::StatsRequest => self.handle_stats_request()?,
+            Message::Shutdown => return Ok(self.handle_shutdown(stream)),
+            other => warn!(
+                tag = other.name(),
+                "Unexpected session socket message, cosmic-session should never send it"
+            ),
+        }
+        Ok(PostAction::Continue)
+    }
+
+    fn handle_hello(&mut self, protocol_version: u32) {
+        if protocol_version != PROTOCOL_VERSION {
+            error!(
+                session_version = protocol_version,
+                our_version = PROTOCOL_VERSION,
+                "cosmic-session speaks an incompatible protocol version"
+            );
+        }
+        self.session_protocol_version = Some(protocol_version);
+    }
+
+    fn handle_ping(&mut self) -> Result<()> {
+        send_message(self, &Message::Pong).context("Failed to answer ping from session")
+    }
+
+    fn handle_set_env(&mut self, variables: HashMap<String, String>) {
+        debug!(?variables, "Session updated environment");
+        self.env.extend(variables);
+    }
+
+    fn handle_unset_env(&mut self, variables: Vec<String>) {
+        debug!(?variables, "Session removed environment variables");
+        for name in variables {
+            self.env.remove(&name);
+        }
+    }
+
+    fn handle_new_privileged_client(&mut self, mut count: usize, stream: &mut StreamWrapper) {
+        // comes straight from the peer, so it must not size any allocation unchecked
+        if count > self.config.max_clients {
+            warn!(
+                count,
+                max_clients = self.config.max_clients,
+                "Session announced more privileged clients than allowed"
+            );
+            notify_client_error(
+                self,
+                format!(
+                    "Session announced {count} privileged clients, more than max_clients {}",
+                    self.config.max_clients
+                ),
+            );
+            count = self.config.max_clients;
+        }
+        let mut buffer = [0; 1];
+        // `count` is an upper bound: the session may send fewer fds, e.g. for clients
+        // that died in the meantime. Slots it didn't fill stay -1 and are skipped,
+        // so only actual fds result in proxied connections.
+        let mut fds = vec![-1; count.min(MAX_FDS_PER_MESSAGE)];
+        let received_count = loop {
+            match stream.stream.recv_with_fd(&mut buffer, &mut fds) {
+                Ok((_, received_count)) => break received_count,
+                // nothing was received yet, the fds are still queued on the socket
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    warn!(?err, "Failed to read file descriptors from session sock");
+                    notify_client_error(
+                        self,
+                        format!("Failed to read file descriptors from session sock: {err}"),
+                    );
+                    return;
+                }
+            }
+        };
+        // SAFETY: just installed by the kernel, so nothing else owns them. Owning all of them
+        // up front closes each exactly once, no matter which of them are refused.
+        let fds = fds
+            .into_iter()
+            .filter(|fd| *fd != -1)
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+            .collect::<Vec<_>>();
+        for fd in fds {
+            self.accept_privileged_client(fd);
+        }
+        // the rest might follow in further control messages
+        stream.pending_fds = count.saturating_sub(received_count);
+        self.recv_pending_fds(stream);
+    }
+
+    /// Collects fds the session sends in further control messages after the first one, one byte each.
+    ///
+    /// Stops once nothing is left to read, and is called again when the socket is readable.
+    /// On EOF or an error the missing fds are given up on, so the read source can handle it.
+    /// A byte arriving without fds is the start of the next message, so the session sent fewer
+    /// than announced. It is kept as part of the next length prefix.
+    fn recv_pending_fds(&mut self, stream: &mut StreamWrapper) {
+        while stream.pending_fds > 0 {
+            let mut byte = [0; 1];
+            let mut space =
+                vec![
+                    0;
+                    rustix::cmsg_space!(ScmRights(stream.pending_fds.min(MAX_FDS_PER_MESSAGE)))
+                ];
+            let mut control = RecvAncillaryBuffer::new(&mut space);
+            let msg = match rustix::net::recvmsg(
+                &stream.stream,
+                &mut [IoSliceMut::new(&mut byte)],
+                &mut control,
+                RecvFlags::DONTWAIT | RecvFlags::CMSG_CLOEXEC,
+            ) {
+                Ok(msg) => msg,
+                Err(Errno::INTR) => continue,
+                Err(Errno::AGAIN) => return,
+                // the length read that follows notices the error on its own
                 Err(err) => {
-                    error!(?err, "Error reading from session socket");
-                    return Ok(PostAction::Remove);
+                    debug!(
+                        ?err,
+                        missing = stream.pending_fds,
+                        "Failed to receive announced privileged clients"
+                    );
+                    stream.pending_fds = 0;
+                    return;
                 }
             };
+            if msg.bytes == 0 {
+                // closed before sending all of them, the length read that follows sees the EOF
+                debug!(
+                    missing = stream.pending_fds,
+                    "Session closed before sending all announced privileged clients"
+                );
+                stream.pending_fds = 0;
+                return;
+            }
 
-            if stream.read_bytes != 0 && stream.read_bytes == stream.size as usize {
-                stream.size = 0;
-                stream.read_bytes = 0;
-                match std::str::from_utf8(&stream.buffer) {
-                    Ok(message) => {
-                        match serde_json::from_str::<'_, Message>(&message) {
-                            Ok(Message::NewPrivilegedClient { count }) => {
-                                let mut buffer = [0; 1];
-                                let mut fds = vec![0; count];
-                                match stream.stream.recv_with_fd(&mut buffer, &mut *fds) {
-                                    Ok((_, received_count)) => {
-                                        assert_eq!(received_count, count);
-                                        for fd in fds.into_iter().take(received_count) {
-                                            if fd == -1 {
-                                                continue;
-                                            }
-                                            let client_stream = unsafe { UnixStream::from_raw_fd(fd) };
-
-                                            let Some(socket_name) = env::var_os("WAYLAND_DISPLAY")
-                                                .map(Into::<PathBuf>::into) else { continue };
-
-                                            let socket_path = if socket_name.is_absolute() {
-                                                socket_name
-                                            } else {
-                                                let Some(mut socket_path) = env::var_os("XDG_RUNTIME_DIR").map(Into::<PathBuf>::into) else { continue };
-                                                if !socket_path.is_absolute() {
-                                                    continue;
-                                                }
-                                                socket_path.push(socket_name);
-                                                socket_path
-                                            };
-                                            match UnixStream::connect(socket_path) {
-                                                Ok(server_stream) => {
-                                                    let client_stream_clone = match client_stream.try_clone() {
-                                                        Ok(stream) => stream,
-                                                        Err(err) => {
-                                                            warn!(?err, "Failed to forward wayland connection");
-                                                            continue;
-                                                        },
-                                                    };
-                                                    let server_stream_clone = match server_stream.try_clone() {
-                                                        Ok(stream) => stream,
-                                                        Err(err) => {
-                                                            warn!(?err, "Failed to forward wayland connection");
-                                                            continue;
-                                                        },
-                                                    };
-
-                                                    if let Err(err) = state.loop_handle.insert_source(Generic::new(server_stream, Interest::READ, Mode::Level), move |_, stream, _| {
-                                                        let mut buf = [0u8; 1024];
-                                                        let mut fds = [0i32; 4];
-                                                        // SAFETY: We don't drop the stream
-                                                        let stream = unsafe { stream.get_mut() };
-                                                        match stream.recv_with_fd(&mut buf, &mut fds) {
-                                                            Ok((bytes, fd_count)) if bytes > 0 || fd_count > 0 => {
-                                                                let mut buf = &buf[0..bytes];
-                                                                let mut fds = &fds[0..fd_count];
-                                                                while !buf.is_empty() {
-                                                                    match client_stream_clone.send_with_fd(buf, fds) {
-                                                                        Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
-                                                                        Ok(0) => {
-                                                                            return Ok(PostAction::Remove);
-                                                                        }
-                                                                        Ok(n) => {
-                                                                            buf = &buf[n..];
-                                                                            fds = &fds[0..0];
-                                                                        },
-                                                                        Err(_) => return Ok(PostAction::Remove),
-                                                                    }
-                                                                }
-                                                                Ok(PostAction::Continue)
-                                                            }
-                                                            Err(err) if err.kind() == ErrorKind::Interrupted => Ok(PostAction::Continue),
-                                                            x => {
-                                                                info!(?x, "client disconnected");
-                                                                let _ = client_stream_clone.shutdown(std::net::Shutdown::Both);
-                                                                let _ = stream.shutdown(std::net::Shutdown::Both);
-                                                                Ok(PostAction::Remove)
-                                                            }
-                                                        }
-                                                    }) {
-                                                        warn!(?err, "Failed to forward wayland connection");
-                                                    }
-                                                    
-                                                    if let Err(err) = state.loop_handle.insert_source(Generic::new(client_stream, Interest::READ, Mode::Level), move |_, stream, _| {
-                                                        let mut buf = [0u8; 1024];
-                                                        let mut fds = [0i32; 4];
-                                                        // SAFETY: We don't drop the stream
-                                                        let stream = unsafe { stream.get_mut() };
-                                                        match stream.recv_with_fd(&mut buf, &mut fds) {
-                                                            Ok((bytes, fd_count)) if bytes > 0 || fd_count > 0 => {
-                                                                let mut buf = &buf[0..bytes];
-                                                                let mut fds = &fds[0..fd_count];
-                                                                while !buf.is_empty() {
-                                                                    match server_stream_clone.send_with_fd(buf, fds) {
-                                                                        Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
-                                                                        Ok(0) => {
-                                                                            return Ok(PostAction::Remove);
-                                                                        }
-                                                                        Ok(n) => {
-                                                                            buf = &buf[n..];
-                                                                            fds = &fds[0..0];
-                                                                        },
-                                                                        Err(_) => return Ok(PostAction::Remove),
-                                                                    }
-                                                                }
-                                                                Ok(PostAction::Continue)
-                                                            }
-                                                            Err(err) if err.kind() == ErrorKind::Interrupted => Ok(PostAction::Continue),
-                                                            x => {
-                                                                info!(?x, "client disconnected");
-                                                                let _ = stream.shutdown(std::net::Shutdown::Both);
-                                                                let _ = server_stream_clone.shutdown(std::net::Shutdown::Both);
-                                                                Ok(PostAction::Remove)
-                                                            }
-                                                        }
-                                                    }) {
-                                                        warn!(?err, "Failed to forward wayland connection");
-                                                    }
-                                                },
-                                                Err(err) => {
-                                                    warn!(?err, "Failed to connect to wayland socket");
-                                                }
-                                            } 
-                                        }
-                                    },
-                                    Err(err) => {
-                                        warn!(?err, "Failed to read file descriptors from session sock");
-                                    }
-                                }
-                            },
-                            Ok(Message::SetEnv { .. }) => warn!("Got SetEnv from session? What is this?"),
-                            _ => warn!("Unknown session socket message, are you using incompatible cosmic-session and cosmic-comp versions?"),
-                        };
-                        Ok(PostAction::Continue)
-                    },
-                    Err(err) => {
-                        warn!(?err, "Invalid message from session sock");
-                        Ok(PostAction::Continue)
-                    }
+            let fds = control
+                .drain()
+                .filter_map(|message| match message {
+                    RecvAncillaryMessage::ScmRights(fds) => Some(fds),
+                    _ => None,
+                })
+                .flatten()
+                .collect::<Vec<_>>();
+            if fds.is_empty() {
+                debug!(
+                    missing = stream.pending_fds,
+                    "Session sent fewer privileged clients than announced"
+                );
+                stream.pending_fds = 0;
+                stream.len[0] = byte[0];
+                stream.len_read = 1;
+                return;
+            }
+            debug!(
+                fds = fds.len(),
+                "Received another batch of privileged clients"
+            );
+            stream.pending_fds = stream.pending_fds.saturating_sub(fds.len());
+            for fd in fds {
+                self.accept_privileged_client(fd);
+            }
+        }
+    }
+
+    /// Starts proxying the privileged client `fd` received from the session.
+    ///
+    /// Refused clients are closed by dropping `fd`.
+    fn accept_privileged_client(&mut self, fd: OwnedFd) {
+        if self.shutting_down {
+            return;
+        }
+        if self.connection_count() >= self.config.max_clients {
+            warn!(
+                max_clients = self.config.max_clients,
+                "Too many privileged clients, refusing new connection"
+            );
+            notify_client_error(
+                self,
+                format!(
+                    "Too many privileged clients (max {})",
+                    self.config.max_clients
+                ),
+            );
+            return;
+        }
+        match is_socket(fd.as_fd()) {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("Privileged client fd is not a socket, refusing it");
+                notify_client_error(self, "Privileged client fd is not a socket".into());
+                return;
+            }
+            Err(err) => {
+                warn!(?err, "Failed to stat privileged client fd");
+                notify_client_error(self, format!("Failed to stat privileged client fd: {err}"));
+                return;
+            }
+        }
+        if let Err(err) = set_cloexec(fd.as_fd()) {
+            warn!(?err, "Failed to set CLOEXEC on privileged client fd");
+            notify_client_error(
+                self,
+                format!("Failed to set CLOEXEC on privileged client fd: {err}"),
+            );
+            return;
+        }
+        let client_stream = UnixStream::from(fd);
+
+        // ids are never reused, so the session can tell reports about clients apart
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+        connect_client(self, id, client_stream, 0);
+    }
+
+    fn handle_spawn_privileged(&mut self, exec: &str, args: &[String]) {
+        if self.shutting_down {
+            return;
+        }
+        if self.connection_count() >= self.config.max_clients {
+            warn!(
+                max_clients = self.config.max_clients,
+                "Too many privileged clients, refusing to spawn {exec}"
+            );
+            notify_client_error(
+                self,
+                format!(
+                    "Too many privileged clients (max {})",
+                    self.config.max_clients
+                ),
+            );
+        } else if let Err(err) = spawn::spawn_privileged(self, exec, args) {
+            warn!(?err, "Failed to spawn privileged client");
+            notify_client_error(self, format!("{err:#}"));
+        }
+    }
+
+    fn handle_close_client(&mut self, id: u64) {
+        if !self.connections.contains_key(&id) {
+            // e.g. the client disconnected on its own in the meantime
+            warn!(id, "Session asked to close an unknown privileged client");
+            notify_client_error(self, format!("Unknown privileged client {id}"));
+            return;
+        }
+        info!(id, "Session closed privileged client");
+        proxy::remove_connection(self, id);
+    }
+
+    fn handle_stats_request(&mut self) -> Result<()> {
+        let counts = self.metrics.session_message_counts();
+        send_message(self, &Message::StatsResponse { counts })
+            .context("Failed to answer stats request from session")
+    }
+
+    fn handle_shutdown(&mut self, stream: &StreamWrapper) -> PostAction {
+        info!(stdin = stream.stdin, "Session requested shutdown");
+        // only the source the message came from is removed, stdin's leaves the session source in place
+        if !stream.stdin {
+            self.session_token = None;
+        }
+        self.request_shutdown();
+        PostAction::Remove
+    }
+}
+
+/// Extracts the `message` tag of a session message, even if it doesn't parse into a [`Message`].
+fn message_tag(message: &str) -> Option<String> {
+    let value = serde_json::from_str::<serde_json::Value>(message).ok()?;
+    value.get("message")?.as_str().map(String::from)
+}
+
+/// Handles the end of `stream`, returning the action for its source.
+///
+/// Only the session socket going away shuts us down, stdin is merely no longer read.
+fn stream_closed(state: &mut State, stream: &StreamWrapper) -> PostAction {
+    if stream.stdin {
+        info!("Stdin closed, no longer reading control messages from it");
+        return PostAction::Remove;
+    }
+    session_closed(state)
+}
+
+/// Shuts down after cosmic-session went away, returning the action for the session source.
+fn session_closed(state: &mut State) -> PostAction {
+    info!("Session socket closed, shutting down");
+    state.session_token = None;
+    state.request_shutdown();
+    PostAction::Remove
+}
+
+/// Shuts down, if cosmic-session hasn't read our `hello` and `set_env` within `timeout`.
+///
+/// Both are only queued at startup, so a session that never reads would otherwise keep us waiting forever.
+pub fn setup_handshake_timeout(state: &mut State, timeout: Duration) -> Result<()> {
+    state
+        .loop_handle
+        .insert_source(Timer::from_duration(timeout), move |_, _, state| {
+            // later messages might be backlogged as well, they don't count
+            if state.handshake_pending > 0 {
+                error!(
+                    ?timeout,
+                    pending = state.handshake_pending,
+                    "cosmic-session didn't read the startup handshake in time, shutting down"
+                );
+                if let Some(token) = state.session_token.take() {
+                    state.loop_handle.remove(token);
                 }
-            } else {
-                Ok(PostAction::Continue)
+                state.request_shutdown();
             }
-        },
-    ).with_context(|| "Failed to init the cosmic session socket source")?;
+            TimeoutAction::Drop
+        })
+        .map_err(|err| err.error)
+        .with_context(|| "Failed to init the handshake timer")?;
 
     Ok(())
 }
+
+/// Periodically pings the session and shuts down, if it stops answering.
+pub fn setup_ping(state: &mut State, interval: Duration) -> Result<()> {
+    state
+        .loop_handle
+        .insert_source(Timer::from_duration(interval), move |_, _, state| {
+            if state.last_pong.elapsed() > interval * PING_TIMEOUT_INTERVALS {
+                error!("cosmic-session stopped responding, shutting down");
+                if let Some(token) = state.session_token.take() {
+                    state.loop_handle.remove(token);
+                }
+                state.request_shutdown();
+                return TimeoutAction::Drop;
+            }
+
+            if let Err(err) = send_message(state, &Message::Ping) {
+                warn!(?err, "Failed to ping cosmic-session");
+            }
+            TimeoutAction::ToDuration(interval)
+        })
+        .map_err(|err| err.error)
+        .with_context(|| "Failed to init the ping timer")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use calloop::EventLoop;
+    use sendfd::SendWithFd;
+    use std::{
+        cell::Cell,
+        io::{Read, Write},
+        os::unix::{io::IntoRawFd, net::UnixListener},
+        rc::Rc,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Runs the session socket against a simulated cosmic-session on the other end of a socketpair.
+    struct Harness {
+        evl: EventLoop<'static, State>,
+        state: State,
+        session: UnixStream,
+    }
+
+    impl Harness {
+        /// Sets up the session socket and consumes the `hello` and `set_env` we greet with.
+        fn new() -> Harness {
+            Harness::with_config(Config::default())
+        }
+
+        fn with_config(config: Config) -> Harness {
+            let env = HashMap::from([(
+                String::from("WAYLAND_DISPLAY"),
+                String::from("wayland-test"),
+            )]);
+            let mut harness = Harness::unread(config, env);
+            assert!(matches!(
+                harness.recv(),
+                Message::Hello {
+                    protocol_version: PROTOCOL_VERSION
+                }
+            ));
+            match harness.recv() {
+                Message::SetEnv { variables } => {
+                    assert_eq!(variables["WAYLAND_DISPLAY"], "wayland-test")
+                }
+                other => panic!("expected set_env, got {other:?}"),
+            }
+            harness
+        }
+
+        /// Sets up the session socket with `env`, leaving the handshake unread.
+        fn unread(config: Config, env: HashMap<String, String>) -> Harness {
+            let evl = EventLoop::try_new().unwrap();
+            let mut state = State::new(&evl, config, env).unwrap();
+            let (ours, session) = UnixStream::pair().unwrap();
+            session
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            setup_socket_from_stream(&mut state, ours).unwrap();
+            Harness {
+                evl,
+                state,
+                session,
+            }
+        }
+
+        /// Lets the event loop handle whatever is ready, the session source reads once per event.
+        fn dispatch(&mut self) {
+            for _ in 0..32 {
+                self.evl.dispatch(Duration::ZERO, &mut self.state).unwrap();
+            }
+        }
+
+        fn send_raw(&mut self, bytes: &[u8]) {
+            self.session.write_all(bytes).unwrap();
+            self.dispatch();
+        }
+
+        fn send(&mut self, message: &Message) {
+            self.send_raw(&encode_message(message).unwrap());
+        }
+
+        /// Announces `count` privileged clients and passes `fds` along, before we get to read either.
+        fn send_clients(&mut self, count: usize, fds: &[RawFd]) {
+            let frame = encode_message(&Message::NewPrivilegedClient { count }).unwrap();
+            self.session.write_all(&frame).unwrap();
+            self.session.send_with_fd(&[0], fds).unwrap();
+            self.dispatch();
+        }
+
+        /// Hands us a privileged client, returning its end and the one `listener` accepted for it.
+        fn forward_client(&mut self, listener: &UnixListener) -> (UnixStream, UnixStream) {
+            let (client, theirs) = UnixStream::pair().unwrap();
+            self.send_clients(1, &[theirs.as_raw_fd()]);
+            let (server, _) = listener.accept().unwrap();
+            assert!(matches!(self.recv(), Message::ClientConnected { .. }));
+            for stream in [&client, &server] {
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(1)))
+                    .unwrap();
+            }
+            (client, server)
+        }
+
+        fn recv(&mut self) -> Message {
+            let mut len = [0; 4];
+            self.session.read_exact(&mut len).unwrap();
+            let mut body = vec![0; u32::from_ne_bytes(len) as usize];
+            self.session.read_exact(&mut body).unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        /// Closes the session end and reports whether we shut down in response.
+        fn close_session(mut self) -> bool {
+            self.session.shutdown(std::net::Shutdown::Both).unwrap();
+            self.dispatch();
+            self.state.shutting_down
+        }
+    }
+
+    /// A wayland socket the privileged clients of a test are forwarded to.
+    fn upstream_listener(name: &str) -> (UnixListener, PathBuf) {
+        let path = env::temp_dir().join(format!("cosmic-alt-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        (listener, path)
+    }
+
+    /// What `fd` refers to, as linked in /proc/self/fd.
+    fn fd_target(fd: BorrowedFd<'_>) -> PathBuf {
+        std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap()
+    }
+
+    /// How many of our fds refer to `target`.
+    ///
+    /// Tests run concurrently, so the total amount of fds would change with the ones other tests open.
+    fn fd_copies(target: &Path) -> usize {
+        std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+            .filter(|link| link == target)
+            .count()
+    }
+
+    #[test]
+    fn encode_message_prefixes_length() {
+        let frame = encode_message(&Message::Ping).unwrap();
+        let len = u32::from_ne_bytes(frame[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, frame.len() - 4);
+        assert_eq!(&frame[4..], br#"{"message":"ping"}"#);
+    }
+
+    #[test]
+    fn encode_message_rejects_oversized() {
+        let variables =
+            HashMap::from([(String::from("BIG"), "x".repeat(MAX_MESSAGE_LEN as usize))]);
+        assert!(matches!(
+            encode_message(&Message::SetEnv { variables }),
+            Err(SessionError::MessageTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn setup_socket_validates_fd() {
+        let evl = EventLoop::try_new().unwrap();
+        let mut state = State::new(&evl, Config::default(), HashMap::new()).unwrap();
+        assert!(matches!(
+            setup_socket(&mut state, -1),
+            Err(SessionError::InvalidFd(-1))
+        ));
+        // far above any fd we have open
+        assert!(matches!(
+            setup_socket(&mut state, 1 << 30),
+            Err(SessionError::ClosedFd(_, Errno::BADF))
+        ));
+        let (pipe, _writer) = rustix::pipe::pipe().unwrap();
+        assert!(matches!(
+            setup_socket(&mut state, pipe.as_raw_fd()),
+            Err(SessionError::NotASocket(_))
+        ));
+        // still ours, so it wasn't closed
+        assert!(rustix::io::fcntl_getfd(&pipe).is_ok());
+        assert!(state.session_socket.is_none());
+
+        let (ours, mut session) = UnixStream::pair().unwrap();
+        setup_socket(&mut state, ours.into_raw_fd()).unwrap();
+        let mut len = [0; 4];
+        session.read_exact(&mut len).unwrap();
+        assert!(state.session_socket.is_some());
+    }
+
+    #[test]
+    fn connects_to_abstract_upstream() {
+        let name = format!("cosmic-alt-test-{}-abstract", std::process::id());
+        let listener =
+            UnixListener::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        let upstream = Upstream::Unix(format!("@{name}"));
+        // there is no path, so the runtime dir doesn't matter
+        let address = UpstreamAddress::resolve(Some(&upstream), None, None).unwrap();
+        assert_eq!(
+            address,
+            UpstreamAddress::Abstract(name.clone().into_bytes())
+        );
+        assert_eq!(address.to_string(), format!("@{name}"));
+        assert!(matches!(address.connect().unwrap(), Stream::Unix(_)));
+        assert!(listener.accept().is_ok());
+    }
+
+    #[test]
+    fn resolves_wayland_sockets_like_libwayland() {
+        let runtime_dir = Path::new("/run/user/1000");
+        assert_eq!(
+            resolve_wayland_socket(OsStr::new("/tmp/wayland-9"), Some(runtime_dir)).unwrap(),
+            Path::new("/tmp/wayland-9")
+        );
+        assert_eq!(
+            resolve_wayland_socket(OsStr::new("/tmp/wayland-9"), None).unwrap(),
+            Path::new("/tmp/wayland-9")
+        );
+        assert_eq!(
+            resolve_wayland_socket(OsStr::new("wayland-1"), Some(runtime_dir)).unwrap(),
+            Path::new("/run/user/1000/wayland-1")
+        );
+        assert_eq!(
+            resolve_wayland_socket(OsStr::new("wayland-1"), None)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            resolve_wayland_socket(OsStr::new(""), Some(runtime_dir))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn connects_to_first_listening_upstream() {
+        // a socket file nobody listens on anymore, like after a compositor crash
+        let (_, dead) = upstream_listener("dead");
+        let (listener, live) = upstream_listener("live");
+        let upstream =
+            [&dead, &live].map(|path| Upstream::Unix(path.to_string_lossy().into_owned()));
+        let (address, _stream) = connect_first(&upstream, None, None).unwrap();
+        assert_eq!(address, UpstreamAddress::Path(live.clone()));
+        assert!(listener.accept().is_ok());
+
+        drop(listener);
+        let err = connect_first(&upstream, None, None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        assert!(err.to_string().starts_with(&*live.to_string_lossy()));
+        for path in [dead, live] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn answers_ping() {
+        let mut harness = Harness::new();
+        harness.send(&Message::Ping);
+        assert!(matches!(harness.recv(), Message::Pong));
+        assert!(harness.close_session());
+    }
+
+    #[test]
+    fn updates_environment() {
+        let mut harness = Harness::new();
+        harness.send(&Message::SetEnv {
+            variables: HashMap::from([(String::from("FOO"), String::from("bar"))]),
+        });
+        assert_eq!(harness.state.env["FOO"], "bar");
+        harness.send(&Message::UnsetEnv {
+            variables: vec![String::from("FOO")],
+        });
+        assert!(!harness.state.env.contains_key("FOO"));
+    }
+
+    struct CountSetEnv(Rc<Cell<usize>>);
+
+    impl MessageHandler for CountSetEnv {
+        fn handle(&mut self, msg: &Message, _state: &mut State) -> HandlerOutcome {
+            match msg {
+                Message::SetEnv { .. } => {
+                    self.0.set(self.0.get() + 1);
+                    HandlerOutcome::Consumed
+                }
+                _ => HandlerOutcome::PassThrough,
+            }
+        }
+    }
+
+    #[test]
+    fn message_handler_consumes_messages() {
+        let mut harness = Harness::new();
+        let count = Rc::new(Cell::new(0));
+        harness
+            .state
+            .set_message_handler(CountSetEnv(count.clone()));
+        for _ in 0..2 {
+            harness.send(&Message::SetEnv {
+                variables: HashMap::from([(String::from("FOO"), String::from("bar"))]),
+            });
+        }
+        assert_eq!(count.get(), 2);
+        assert!(!harness.state.env.contains_key("FOO"));
+        harness.send(&Message::Ping);
+        assert!(matches!(harness.recv(), Message::Pong));
+    }
+
+    #[test]
+    fn reassembles_fragmented_frames() {
+        let mut harness = Harness::new();
+        let mut bytes = encode_message(&Message::Hello {
+            protocol_version: PROTOCOL_VERSION,
+        })
+        .unwrap();
+        bytes.extend(encode_message(&Message::Ping).unwrap());
+        for byte in bytes {
+            harness.send_raw(&[byte]);
+        }
+        assert_eq!(
+            harness.state.session_protocol_version,
+            Some(PROTOCOL_VERSION)
+        );
+        assert!(matches!(harness.recv(), Message::Pong));
+    }
+
+    #[test]
+    fn oneshot_reads_whole_messages() {
+        let mut harness = Harness::with_config(Config {
+            session_oneshot: true,
+            ..Config::default()
+        });
+        // the length prefix and the body are read in a single wakeup
+        harness
+            .session
+            .write_all(&encode_message(&Message::Ping).unwrap())
+            .unwrap();
+        harness
+            .evl
+            .dispatch(Duration::ZERO, &mut harness.state)
+            .unwrap();
+        assert!(matches!(harness.recv(), Message::Pong));
+
+        // a message arriving in pieces re-arms the source until it is complete
+        for byte in encode_message(&Message::Ping).unwrap() {
+            harness.send_raw(&[byte]);
+        }
+        assert!(matches!(harness.recv(), Message::Pong));
+        assert!(harness.close_session());
+    }
+
+    #[test]
+    fn skips_invalid_messages() {
+        let mut harness = Harness::new();
+        let body = br#"{"message":"no_such_message"}"#;
+        let mut bytes = (body.len() as u32).to_ne_bytes().to_vec();
+        bytes.extend_from_slice(body);
+        bytes.extend(0u32.to_ne_bytes());
+        harness.send_raw(&bytes);
+        harness.send(&Message::Ping);
+        assert!(matches!(harness.recv(), Message::Pong));
+    }
+
+    #[test]
+    fn shuts_down_on_oversized_prefix() {
+        let mut harness = Harness::new();
+        harness.send_raw(&u32::MAX.to_ne_bytes());
+        assert!(harness.state.shutting_down);
+    }
+
+    #[test]
+    fn shuts_down_on_request() {
+        let mut harness = Harness::new();
+        harness
+            .session
+            .write_all(&encode_message(&Message::Shutdown).unwrap())
+            .unwrap();
+        // only returns once the loop was stopped
+        harness.evl.run(None, &mut harness.state, |_| {}).unwrap();
+        assert!(harness.state.shutting_down);
+        assert!(harness.state.session_token.is_none());
+    }
+
+    #[test]
+    fn shutdown_over_stdin_keeps_session_source() {
+        let mut harness = Harness::new();
+        let mut stdin = StreamWrapper::from(UnixStream::pair().unwrap().0);
+        stdin.stdin = true;
+        let action = harness
+            .state
+            .handle_message(Message::Shutdown, &mut stdin)
+            .unwrap();
+        assert_eq!(action, PostAction::Remove);
+        assert!(harness.state.session_token.is_some());
+        harness.dispatch();
+        assert!(harness.state.shutting_down);
+    }
+
+    #[test]
+    fn handles_messages_piped_over_stdin() {
+        let mut harness = Harness::new();
+        let (stdin, writer) = rustix::pipe::pipe().unwrap();
+        setup_stdin_control_from_fd(&mut harness.state, stdin).unwrap();
+        let variables = HashMap::from([(String::from("FOO"), String::from("bar"))]);
+        let frame = encode_message(&Message::SetEnv { variables }).unwrap();
+        // in pieces, like a slow writer would
+        for piece in frame.chunks(5) {
+            rustix::io::write(&writer, piece).unwrap();
+            harness.dispatch();
+        }
+        assert_eq!(harness.state.env["FOO"], "bar");
+
+        // the end of stdin leaves the session alone
+        drop(writer);
+        harness.dispatch();
+        assert!(!harness.state.shutting_down);
+        harness.send(&Message::Ping);
+        assert!(matches!(harness.recv(), Message::Pong));
+    }
+
+    #[test]
+    fn reads_control_file_on_stdin_at_once() {
+        let mut harness = Harness::new();
+        let path = env::temp_dir().join(format!("cosmic-alt-test-{}-stdin", std::process::id()));
+        let variables = HashMap::from([(String::from("FOO"), String::from("file"))]);
+        let mut frames = encode_message(&Message::SetEnv { variables }).unwrap();
+        frames.extend(
+            encode_message(&Message::UnsetEnv {
+                variables: vec![String::from("WAYLAND_DISPLAY")],
+            })
+            .unwrap(),
+        );
+        std::fs::write(&path, frames).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        setup_stdin_control_from_fd(&mut harness.state, file.into()).unwrap();
+        assert_eq!(harness.state.env["FOO"], "file");
+        assert!(!harness.state.env.contains_key("WAYLAND_DISPLAY"));
+    }
+
+    #[test]
+    fn shuts_down_when_session_closes() {
+        assert!(Harness::new().close_session());
+    }
+
+    #[test]
+    fn forwards_privileged_clients() {
+        let (listener, path) = upstream_listener("forward");
+        let mut harness = Harness::new();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let (mut client, mut server) = harness.forward_client(&listener);
+        assert_eq!(harness.state.connection_count(), 1);
+
+        let mut buf = [0; 7];
+        client.write_all(b"request").unwrap();
+        harness.dispatch();
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"request");
+        server.write_all(b"replied").unwrap();
+        harness.dispatch();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"replied");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn forwards_privileged_clients_over_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut harness = Harness::new();
+        harness.state.upstream = vec![Upstream::Tcp(listener.local_addr().unwrap().to_string())];
+        let (mut client, theirs) = UnixStream::pair().unwrap();
+        harness.send_clients(1, &[theirs.as_raw_fd()]);
+        let (mut server, _) = listener.accept().unwrap();
+        assert!(matches!(harness.recv(), Message::ClientConnected { id: 0 }));
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let mut buf = [0; 7];
+        client.write_all(b"request").unwrap();
+        harness.dispatch();
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"request");
+        server.write_all(b"replied").unwrap();
+        harness.dispatch();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"replied");
+
+        // fds can't go over the network
+        client.send_with_fd(b"fd", &[theirs.as_raw_fd()]).unwrap();
+        harness.dispatch();
+        assert!(matches!(
+            harness.recv(),
+            Message::ClientDisconnected { id: 0 }
+        ));
+        assert_eq!(server.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn forwards_fds_of_privileged_clients() {
+        let (listener, path) = upstream_listener("fds");
+        let mut harness = Harness::new();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let (client, server) = harness.forward_client(&listener);
+
+        let sockets = (0..5)
+            .flat_map(|_| <[UnixStream; 2]>::from(UnixStream::pair().unwrap()))
+            .collect::<Vec<_>>();
+        let raw_fds = sockets.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+        client.send_with_fd(b"fds", &raw_fds).unwrap();
+        harness.dispatch();
+
+        let mut buf = [0; 3];
+        let mut fds = [-1; 28];
+        let (bytes, count) = server.recv_with_fd(&mut buf, &mut fds).unwrap();
+        assert_eq!((bytes, count, &buf), (3, 10, b"fds"));
+        // SAFETY: just received, nothing else owns them
+        let received = fds[..count]
+            .iter()
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(*fd) })
+            .collect::<Vec<_>>();
+        let inode = |fd: BorrowedFd<'_>| rustix::fs::fstat(fd).unwrap().st_ino;
+        for (sent, received) in sockets.iter().zip(&received) {
+            assert_eq!(inode(sent.as_fd()), inode(received.as_fd()));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn receives_fds_across_control_messages() {
+        let (listener, path) = upstream_listener("batches");
+        let mut harness = Harness::new();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let (_first, first) = UnixStream::pair().unwrap();
+        let (_second, second) = UnixStream::pair().unwrap();
+        let frame = encode_message(&Message::NewPrivilegedClient { count: 2 }).unwrap();
+        harness.session.write_all(&frame).unwrap();
+        harness
+            .session
+            .send_with_fd(&[0], &[first.as_raw_fd()])
+            .unwrap();
+        harness
+            .session
+            .send_with_fd(&[0], &[second.as_raw_fd()])
+            .unwrap();
+        harness.dispatch();
+        assert!(matches!(harness.recv(), Message::ClientConnected { id: 0 }));
+        assert!(matches!(harness.recv(), Message::ClientConnected { id: 1 }));
+        assert_eq!(harness.state.connection_count(), 2);
+        let _servers = [listener.accept().unwrap(), listener.accept().unwrap()];
+
+        // the next message isn't mistaken for a missing fd
+        harness.send(&Message::Ping);
+        assert!(matches!(harness.recv(), Message::Pong));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn receives_fds_despite_signal() {
+        static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn count_signal(_: libc::c_int) {
+            SIGNALS.fetch_add(1, Ordering::SeqCst);
+        }
+        // without SA_RESTART, so the signal interrupts the recv waiting for the fd
+        // SAFETY: the handler only touches an atomic
+        unsafe {
+            let mut action = std::mem::zeroed::<libc::sigaction>();
+            action.sa_sigaction = count_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+                0
+            );
+        }
+
+        let (listener, path) = upstream_listener("signal");
+        let mut harness = Harness::new();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let (_client, theirs) = UnixStream::pair().unwrap();
+        let frame = encode_message(&Message::NewPrivilegedClient { count: 1 }).unwrap();
+        harness.session.write_all(&frame).unwrap();
+        let session = harness.session.try_clone().unwrap();
+        // SAFETY: always safe to call
+        let thread = unsafe { libc::pthread_self() };
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            // SAFETY: the test thread is blocked in dispatch until the fd arrives
+            assert_eq!(unsafe { libc::pthread_kill(thread, libc::SIGUSR1) }, 0);
+            std::thread::sleep(Duration::from_millis(50));
+            session.send_with_fd(&[0], &[theirs.as_raw_fd()]).unwrap();
+        });
+        harness.dispatch();
+        sender.join().unwrap();
+        assert_eq!(SIGNALS.load(Ordering::SeqCst), 1);
+        assert!(matches!(harness.recv(), Message::ClientConnected { id: 0 }));
+        let _server = listener.accept().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn shuts_down_when_session_closes_before_all_fds() {
+        let (_listener, path) = upstream_listener("short");
+        let mut harness = Harness::new();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let (_client, theirs) = UnixStream::pair().unwrap();
+        harness.send_clients(2, &[theirs.as_raw_fd()]);
+        assert!(matches!(harness.recv(), Message::ClientConnected { id: 0 }));
+        assert!(harness.close_session());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn retries_connecting_until_upstream_listens() {
+        let mut harness = Harness::with_config(Config {
+            connect_retry_delays_ms: vec![20],
+            ..Config::default()
+        });
+        let (listener, path) = upstream_listener("retry");
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let (_client, theirs) = UnixStream::pair().unwrap();
+        harness.send_clients(1, &[theirs.as_raw_fd()]);
+        assert_eq!(harness.state.connection_count(), 0);
+
+        let (listener, path) = upstream_listener("retry");
+        std::thread::sleep(Duration::from_millis(30));
+        harness.dispatch();
+        assert!(matches!(harness.recv(), Message::ClientConnected { id: 0 }));
+        let _server = listener.accept().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn looks_up_runtime_dir_once_for_many_clients() {
+        let mut harness = Harness::with_config(Config {
+            connect_retry_delays_ms: Vec::new(),
+            ..Config::default()
+        });
+        let (listener, path) = upstream_listener("lookups");
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let clients = (0..100)
+            .map(|_| UnixStream::pair().unwrap())
+            .collect::<Vec<_>>();
+        let fds = clients
+            .iter()
+            .map(|(_, theirs)| theirs.as_raw_fd())
+            .collect::<Vec<_>>();
+        // an unreachable upstream keeps the runtime dir cached
+        harness.send_clients(100, &fds);
+        for _ in 0..100 {
+            assert!(matches!(harness.recv(), Message::ClientError { .. }));
+        }
+        assert_eq!(harness.state.metrics.upstream_lookups_total, 1);
+
+        let (listener, path) = upstream_listener("lookups");
+        harness.send_clients(100, &fds);
+        for _ in 0..100 {
+            assert!(matches!(harness.recv(), Message::ClientConnected { .. }));
+        }
+        assert_eq!(harness.state.connection_count(), 100);
+        assert_eq!(harness.state.metrics.upstream_lookups_total, 1);
+        drop(listener);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn closes_client_after_connect_failure() {
+        let mut harness = Harness::with_config(Config {
+            connect_retry_delays_ms: Vec::new(),
+            ..Config::default()
+        });
+        let (_, path) = upstream_listener("refused");
+        std::fs::remove_file(&path).unwrap();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let (mut client, theirs) = UnixStream::pair().unwrap();
+        let target = fd_target(theirs.as_fd());
+        harness.send_clients(1, &[theirs.as_raw_fd()]);
+        drop(theirs);
+        assert!(matches!(harness.recv(), Message::ClientError { .. }));
+        assert_eq!(fd_copies(&target), 0);
+        // no copy of its peer is left open
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn closes_client_fds_exactly_once() {
+        // a copy closed twice would close the fd reusing its number, which std aborts on in debug builds
+        let (listener, path) = upstream_listener("once");
+        let mut harness = Harness::new();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let (_client, theirs) = UnixStream::pair().unwrap();
+        let target = fd_target(theirs.as_fd());
+        harness.send_clients(1, &[theirs.as_raw_fd()]);
+        drop(theirs);
+        let _server = listener.accept().unwrap();
+        assert!(matches!(harness.recv(), Message::ClientConnected { id: 0 }));
+        // the connection's and the one of its source
+        assert_eq!(fd_copies(&target), 2);
+        harness.send(&Message::CloseClient { id: 0 });
+        assert!(matches!(
+            harness.recv(),
+            Message::ClientDisconnected { id: 0 }
+        ));
+        assert_eq!(fd_copies(&target), 0);
+
+        // both ends of a pipe link to the same target
+        let (pipe, _) = rustix::pipe::pipe().unwrap();
+        let target = fd_target(pipe.as_fd());
+        harness.send_clients(1, &[pipe.as_raw_fd()]);
+        drop(pipe);
+        assert!(matches!(harness.recv(), Message::ClientError { .. }));
+        assert_eq!(fd_copies(&target), 0);
+
+        harness.state.config.max_clients = 0;
+        let (_client, theirs) = UnixStream::pair().unwrap();
+        let target = fd_target(theirs.as_fd());
+        harness.send_clients(1, &[theirs.as_raw_fd()]);
+        drop(theirs);
+        assert!(matches!(harness.recv(), Message::ClientError { .. }));
+        assert_eq!(fd_copies(&target), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn closes_only_the_requested_client() {
+        let (listener, path) = upstream_listener("close");
+        let mut harness = Harness::new();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let (mut first, _first_server) = harness.forward_client(&listener);
+        let (mut second, mut server) = harness.forward_client(&listener);
+        harness.send(&Message::CloseClient { id: 0 });
+        assert!(matches!(
+            harness.recv(),
+            Message::ClientDisconnected { id: 0 }
+        ));
+        assert_eq!(first.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(harness.state.connection_count(), 1);
+
+        let mut buf = [0; 7];
+        second.write_all(b"request").unwrap();
+        harness.dispatch();
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"request");
+        server.write_all(b"replied").unwrap();
+        harness.dispatch();
+        second.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"replied");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn answers_stats_request() {
+        let (listener, path) = upstream_listener("stats");
+        let mut harness = Harness::new();
+        harness.state.upstream = vec![Upstream::Unix(path.to_string_lossy().into_owned())];
+        let _first = harness.forward_client(&listener);
+        let _second = harness.forward_client(&listener);
+        harness.send(&Message::StatsRequest);
+        match harness.recv() {
+            Message::StatsResponse { counts } => {
+                assert_eq!(counts["new_privileged_client"], 2);
+                assert_eq!(counts["stats_request"], 1);
+                assert!(!counts.contains_key("ping"));
+            }
+            other => panic!("expected stats_response, got {other:?}"),
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bounds_announced_client_count() {
+        let mut harness = Harness::new();
+        let (_client, theirs) = UnixStream::pair().unwrap();
+        harness.send_clients(usize::MAX, &[theirs.as_raw_fd()]);
+        assert!(matches!(harness.recv(), Message::ClientError { .. }));
+    }
+
+    #[test]
+    fn handshake_timeout_fires_for_unread_handshake() {
+        // larger than the socket buffer, so it stays queued
+        let env = HashMap::from([(
+            String::from("BIG"),
+            "x".repeat(MAX_MESSAGE_LEN as usize / 2),
+        )]);
+        let mut harness = Harness::unread(Config::default(), env);
+        assert!(harness.state.handshake_pending > 0);
+        setup_handshake_timeout(&mut harness.state, Duration::from_millis(10)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        harness.dispatch();
+        assert!(harness.state.shutting_down);
+    }
+
+    #[test]
+    fn handshake_timeout_ignores_later_backlog() {
+        let mut harness = Harness::new();
+        assert_eq!(harness.state.handshake_pending, 0);
+        setup_handshake_timeout(&mut harness.state, Duration::from_millis(10)).unwrap();
+        while harness.state.session_writer.is_none() {
+            notify_client_error(&mut harness.state, "x".repeat(64 * 1024));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        harness.dispatch();
+        assert!(!harness.state.shutting_down);
+    }
+}
@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::Path,
+};
+use tracing::debug;
+
+/// Removes the socket at `path` left behind by an instance that is gone, so it can be bound again.
+///
+/// Anything but a socket is left alone, as is the socket of an instance still accepting connections.
+pub fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        Ok(_) => {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                "file exists and is not a socket",
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            "another instance is listening on it",
+        ));
+    }
+    fs::remove_file(path)?;
+    debug!(?path, "Removed stale socket");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, os::unix::net::UnixListener};
+
+    #[test]
+    fn remove_stale_socket_only_removes_dead_sockets() {
+        let dir = env::temp_dir().join(format!("cosmic-alt-test-{}-stale", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let file = dir.join("file");
+        fs::write(&file, "keep").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert!(file.exists());
+
+        let socket = dir.join("socket");
+        let listener = UnixListener::bind(&socket).unwrap();
+        assert!(remove_stale_socket(&socket).is_err());
+        drop(listener);
+        remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+        remove_stale_socket(&socket).unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::{Context, Result};
+use calloop::signals::{Signal, Signals};
+use rustix::io::FdFlags;
+use std::{
+    os::unix::{
+        io::{AsRawFd, BorrowedFd},
+        net::UnixStream,
+        process::{CommandExt, ExitStatusExt},
+    },
+    process::{Child, Command, Stdio},
+};
+use tracing::{debug, info, warn};
+
+use crate::{proxy, session, State};
+
+/// A privileged client we spawned ourselves.
+#[derive(Debug)]
+pub struct SpawnedClient {
+    child: Child,
+    /// Id of the proxied connection handed to the child
+    connection: u64,
+}
+
+/// Runs `exec` as a privileged client, handing it a proxied connection via `WAYLAND_SOCKET`.
+///
+/// The child gets the session environment and is reaped by the source installed in [`setup_reaper`].
+pub fn spawn_privileged(state: &mut State, exec: &str, args: &[String]) -> Result<()> {
+    let (client_stream, child_stream) =
+        UnixStream::pair().context("Failed to create socket pair")?;
+    let child_fd = child_stream.as_raw_fd();
+
+    let mut command = Command::new(exec);
+    command
+        .args(args)
+        .envs(&state.env)
+        .env("WAYLAND_SOCKET", child_fd.to_string())
+        .stdin(Stdio::null());
+    // SAFETY: only calls fcntl, which is async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            // std creates sockets with CLOEXEC, the child has to inherit this one though
+            let fd = BorrowedFd::borrow_raw(child_fd);
+            rustix::io::fcntl_setfd(fd, FdFlags::empty())?;
+            Ok(())
+        });
+    }
+    let child = command
+        .spawn()
+        .with_context(|| format!("Failed to spawn {exec}"))?;
+    // the child has its own copy now
+    drop(child_stream);
+
+    let pid = child.id();
+    let id = state.next_connection_id;
+    state.next_connection_id += 1;
+    info!(pid, id, exec, "Spawned privileged client");
+    state.children.insert(
+        pid,
+        SpawnedClient {
+            child,
+            connection: id,
+        },
+    );
+
+    session::connect_client(state, id, client_stream, 0);
+    Ok(())
+}
+
+/// Reaps spawned privileged clients on `SIGCHLD` and closes the connections they left behind.
+pub fn setup_reaper(state: &mut State) -> Result<()> {
+    let signals = Signals::new(&[Signal::SIGCHLD]).context("Failed to setup SIGCHLD handling")?;
+    state
+        .loop_handle
+        .insert_source(signals, |_, _, state| reap_children(state))
+        .map_err(|err| err.error)
+        .context("Failed to init the SIGCHLD source")?;
+    Ok(())
+}
+
+fn reap_children(state: &mut State) {
+    // pending signals are coalesced, so one SIGCHLD might stand for several exited children
+    let mut exited = Vec::new();
+    for (pid, spawned) in &mut state.children {
+        match spawned.child.try_wait() {
+            Ok(Some(status)) => {
+                info!(
+                    pid,
+                    id = spawned.connection,
+                    code = status.code(),
+                    signal = status.signal(),
+                    "Privileged client exited"
+                );
+                exited.push(*pid);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                warn!(pid, ?err, "Failed to reap privileged client");
+                exited.push(*pid);
+            }
+        }
+    }
+
+    for pid in exited {
+        if let Some(spawned) = state.children.remove(&pid) {
+            if state.connections.contains_key(&spawned.connection) {
+                // e.g. a daemonizing child might have passed its socket on
+                debug!(
+                    pid,
+                    id = spawned.connection,
+                    "Closing connection of exited privileged client"
+                );
+                proxy::remove_connection(state, spawned.connection);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use calloop::EventLoop;
+    use rustix::process::{Pid, WaitId, WaitidOptions};
+    use std::{collections::HashMap, io::Read, time::Duration};
+
+    /// State whose first privileged client is forwarded to the returned end of a socketpair.
+    fn state(evl: &EventLoop<'static, State>) -> (State, UnixStream) {
+        let mut state = State::new(evl, Config::default(), HashMap::new()).unwrap();
+        let (ours, server) = UnixStream::pair().unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        state.wayland_socket = Some(ours);
+        (state, server)
+    }
+
+    #[test]
+    fn spawns_privileged_client() {
+        let evl = EventLoop::try_new().unwrap();
+        let (mut state, _server) = state(&evl);
+        spawn_privileged(&mut state, "/bin/echo", &[String::from("hi")]).unwrap();
+        assert_eq!(state.children.len(), 1);
+        assert_eq!(state.children.values().next().unwrap().connection, 0);
+        assert_eq!(state.connection_count(), 1);
+        assert!(spawn_privileged(&mut state, "/nonexistent", &[]).is_err());
+        assert_eq!(state.children.len(), 1);
+    }
+
+    #[test]
+    fn hands_connection_to_child() {
+        let mut evl = EventLoop::try_new().unwrap();
+        let (mut state, mut server) = state(&evl);
+        // unlike bash, dash only redirects single digit fds
+        let args = ["-c", "printf hello >&$WAYLAND_SOCKET"].map(String::from);
+        spawn_privileged(&mut state, "bash", &args).unwrap();
+        let mut buf = [0; 5];
+        for _ in 0..100 {
+            evl.dispatch(Duration::from_millis(10), &mut state).unwrap();
+            if server.read_exact(&mut buf).is_ok() {
+                break;
+            }
+        }
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn reaps_exited_children() {
+        let mut evl = EventLoop::try_new().unwrap();
+        let (mut state, _server) = state(&evl);
+        // blocks SIGCHLD on this thread only
+        setup_reaper(&mut state).unwrap();
+        spawn_privileged(&mut state, "/bin/true", &[]).unwrap();
+        let pid = *state.children.keys().next().unwrap();
+        rustix::process::waitid(
+            WaitId::Pid(Pid::from_raw(pid as i32).unwrap()),
+            WaitidOptions::EXITED | WaitidOptions::NOWAIT,
+        )
+        .unwrap();
+        // the kernel may have picked any other test thread, which doesn't block it
+        // SAFETY: signals this thread, which has SIGCHLD blocked and pending for the signalfd
+        unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGCHLD) };
+        evl.dispatch(Duration::from_secs(1), &mut state).unwrap();
+        assert!(state.children.is_empty());
+        assert_eq!(state.connection_count(), 0);
+    }
+}